@@ -0,0 +1,55 @@
+use std::{env, fs, path::Path, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // builds from a crate tarball have no git, and just go without the commit
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let git = Path::new(&manifest_dir).join(".git");
+    if git.exists() {
+        watch_git(&git);
+        let commit = Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .current_dir(&manifest_dir)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok());
+        if let Some(commit) = commit {
+            println!("cargo:rustc-env=OCTO_GIT_COMMIT={}", commit.trim());
+        }
+    }
+
+    // cargo sets CARGO_FEATURE_<NAME> for every enabled feature, so new ones
+    // show up in --version without listing them anywhere
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(var, _)| {
+            let feature = var.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=OCTO_FEATURES={}", features.join(", "));
+}
+
+/// Reruns the build when the commit checked out in `git` changes. Worktrees,
+/// where `.git` is only a file pointing elsewhere, aren't watched.
+fn watch_git(git: &Path) {
+    let watch = |path: &Path| println!("cargo:rerun-if-changed={}", path.display());
+    let Ok(head) = fs::read_to_string(git.join("HEAD")) else {
+        return;
+    };
+    // HEAD moves on checkout, and the branch it points to on commit, which
+    // lands in packed-refs instead once refs are packed
+    watch(&git.join("HEAD"));
+    if let Some(branch) = head.trim().strip_prefix("ref: ") {
+        let branch = git.join(branch);
+        if branch.exists() {
+            watch(&branch);
+        }
+    }
+    if git.join("packed-refs").exists() {
+        watch(&git.join("packed-refs"));
+    }
+}
@@ -0,0 +1,644 @@
+pub const USAGE: &str = "Usage: octo-dl [options] <public url(s)>
+
+Links are also read from stdin, one or more per line, when given `-` or
+when no links are given and stdin isn't a terminal.
+
+Options:
+  -q, --quiet                     only print errors and the final summary
+  -v, --verbose                   also print fetched links and skipped files
+      --no-progress               print a line per finished file instead of progress bars
+      --progress-style <style>    draw progress bars with `unicode` (default) or `ascii`
+      --strict                    also exit non-zero when files were skipped
+      --fail-fast                 stop everything at the first link or file that fails
+      --max-errors <count>        don't start more downloads once this many have
+                                  failed, letting the running ones finish
+      --check-sizes               check files already on disk have the right size
+                                  instead of downloading, without reading them
+      --stdout                    write the file to stdout instead of saving it, for
+                                  links to a single file
+      --anonymous                 don't log in, MEGA_EMAIL and MEGA_PASSWORD are not needed
+      --exec <command>            run a command after each file is downloaded, see below
+      --timeline                  print when each file downloaded after the summary
+      --path-template <template>  where to save files, see below
+      --fetch-retries <count>     retry fetching a link this many times (default 3)
+      --concurrent-fetches <count>
+                                  fetch this many links at once (default 4, up to 32)
+      --parallel <count>          download this many files at once (default 20, up to
+                                  100), plus one more under 16 MiB
+      --ext <list>                only download files with one of these comma separated
+                                  extensions, e.g. mkv,mp4 (any case)
+      --min-size <size>           only download files at least this big
+      --max-size <size>           only download files at most this big
+      --assume-speed <size>       estimate how long downloads take at this speed per
+                                  second, instead of the average of earlier runs
+      --max-files <count>         refuse to download more than this many files
+      --max-total <size>          refuse to download more than this much in total
+      --part-suffix <suffix>      download into <name><suffix> until complete (default
+                                  .part), empty to write files in place
+      --hidden-parts              also prefix unfinished files with a dot, so sync
+                                  tools skip them
+      --trust-parts               keep part files an earlier run left at full size
+                                  instead of downloading them again, checking only
+                                  their size
+      --preserve-mtime            give downloaded files the modification time MEGA
+                                  has for them, instead of when they downloaded
+      --log-file <path>           also append what happened to this file, moving it
+                                  to <path>.1 once it's over 10 MiB
+  -y, --yes                       don't ask before large downloads, and download even
+                                  when over --max-files/--max-total
+  -V, --version                   print the version, commit and enabled features
+  -h, --help                      print this message
+
+Sizes are in bytes, or with a K, M, G or T suffix in powers of 1024, e.g.
+500k, 1.5G, 100MB or 2GiB.
+
+--exec splits its command on whitespace and runs it without a shell, after
+replacing {path}, {name} and {size} (in bytes) in each word, e.g.
+  --exec 'unzip -o {path}'
+
+--path-template lays files out using {root} (the shared folder), {folder}
+(the folders between it and the file), {name}, {ext} and {date} (today, as
+YYYY-MM-DD), and must include {name}, e.g.
+  --path-template '{date}/{root}/{folder}/{name}'
+By default files are saved under their parent and grandparent folder names.
+
+When run from a terminal, octo-dl asks before downloading more than 10 GiB
+or going over --max-files/--max-total. Otherwise it goes ahead unless over
+the limits.
+
+Named pipes that already exist are written to directly, without a
+partial file, so a player can read from one as the file downloads.
+
+Progress bars are replaced by plain lines when stderr is not a terminal or
+TERM=dumb, and drawn without colors when NO_COLOR is set.
+
+Environment:
+  MEGA_EMAIL, MEGA_PASSWORD       account to log in with, unless --anonymous
+  MEGA_MFA                        one-time code, if the account uses 2FA
+  MEGA_MFA_SECRET                 2FA secret to generate the code from instead
+                                  (needs the `totp` feature)
+  OCTO_CONNECT_TIMEOUT            seconds to wait for a connection (default 30)
+  OCTO_REQUEST_TIMEOUT            seconds any single request may take, file
+                                  downloads included (default: no limit)
+  OCTO_STALL_TIMEOUT              seconds a download may go without receiving
+                                  data before it fails (default 60)
+  XDG_STATE_HOME                  where the average download speed of earlier
+                                  runs is kept (default ~/.local/state)
+
+Timeouts set to 0 are disabled.
+
+Exit status:
+  0  everything was downloaded (or checked)
+  1  some files failed (or, with --strict, were skipped)
+  2  bad command line arguments
+  3  files failed and nothing was downloaded (or checked)
+  4  nothing was downloaded for being over --max-files/--max-total, or
+     because the download wasn't confirmed
+  5  logging in to MEGA failed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Unicode,
+    Ascii,
+    Off,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub urls: Vec<String>,
+    /// Read links from stdin too, from `-` or piped input.
+    pub stdin: bool,
+    pub verbosity: Verbosity,
+    /// `None` when neither `--no-progress` nor `--progress-style` was given,
+    /// leaving the choice to terminal detection.
+    pub progress: Option<Progress>,
+    pub strict: bool,
+    pub fail_fast: bool,
+    pub max_errors: Option<usize>,
+    pub check_sizes: bool,
+    pub stdout: bool,
+    pub anonymous: bool,
+    /// The `--exec` command, split into words.
+    pub exec: Option<Vec<String>>,
+    pub timeline: bool,
+    pub path_template: Option<String>,
+    /// Lowercase extensions from `--ext`, without dots.
+    pub extensions: Option<Vec<String>>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub assume_speed: Option<u64>,
+    pub max_files: Option<usize>,
+    pub max_total: Option<u64>,
+    pub yes: bool,
+    pub fetch_retries: u32,
+    pub concurrent_fetches: usize,
+    pub parallel: usize,
+    pub log_file: Option<String>,
+    pub part_suffix: String,
+    pub hidden_parts: bool,
+    pub trust_parts: bool,
+    pub preserve_mtime: bool,
+    pub version: bool,
+    pub help: bool,
+}
+
+impl Args {
+    /// Parses the command line. Without any links, `stdin_piped` decides
+    /// between reading them from stdin and failing.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        stdin_piped: bool,
+    ) -> Result<Self, String> {
+        let mut parsed = Args {
+            urls: vec![],
+            stdin: false,
+            verbosity: Verbosity::Normal,
+            progress: None,
+            strict: false,
+            fail_fast: false,
+            max_errors: None,
+            check_sizes: false,
+            stdout: false,
+            anonymous: false,
+            exec: None,
+            timeline: false,
+            path_template: None,
+            extensions: None,
+            min_size: None,
+            max_size: None,
+            assume_speed: None,
+            max_files: None,
+            max_total: None,
+            yes: false,
+            fetch_retries: 3,
+            concurrent_fetches: 4,
+            parallel: 20,
+            log_file: None,
+            part_suffix: ".part".to_string(),
+            hidden_parts: false,
+            trust_parts: false,
+            preserve_mtime: false,
+            version: false,
+            help: false,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+                _ => (arg.as_str(), None),
+            };
+
+            match flag {
+                "-q" | "--quiet" => parsed.verbosity = Verbosity::Quiet,
+                "-v" | "--verbose" => parsed.verbosity = Verbosity::Verbose,
+                "--no-progress" => parsed.progress = Some(Progress::Off),
+                "--progress-style" => {
+                    parsed.progress = Some(match value(flag, inline, &mut args)?.as_str() {
+                        "unicode" => Progress::Unicode,
+                        "ascii" => Progress::Ascii,
+                        other => return Err(format!("unknown progress style {other}")),
+                    })
+                }
+                "--strict" => parsed.strict = true,
+                "--fail-fast" => parsed.fail_fast = true,
+                "--max-errors" => parsed.max_errors = Some(number(flag, inline, &mut args)?),
+                "--check-sizes" => parsed.check_sizes = true,
+                "--stdout" => parsed.stdout = true,
+                "--anonymous" => parsed.anonymous = true,
+                "--timeline" => parsed.timeline = true,
+                "--path-template" => {
+                    let template = value(flag, inline, &mut args)?;
+                    check_placeholders(
+                        flag,
+                        &template,
+                        &["root", "folder", "name", "ext", "date"],
+                    )?;
+                    if !template.contains("{name}") {
+                        return Err(format!(
+                            "{flag} needs {{name}}, or every file gets the same path"
+                        ));
+                    }
+                    parsed.path_template = Some(template);
+                }
+                "--ext" => {
+                    parsed.extensions = Some(parse_extensions(&value(flag, inline, &mut args)?)?)
+                }
+                "--max-files" => parsed.max_files = Some(number(flag, inline, &mut args)?),
+                "--max-total" => parsed.max_total = Some(size(flag, inline, &mut args)?),
+                "--assume-speed" => parsed.assume_speed = Some(size(flag, inline, &mut args)?),
+                "--min-size" => parsed.min_size = Some(size(flag, inline, &mut args)?),
+                "--max-size" => parsed.max_size = Some(size(flag, inline, &mut args)?),
+                "-y" | "--yes" => parsed.yes = true,
+                "--fetch-retries" => parsed.fetch_retries = number(flag, inline, &mut args)?,
+                "--concurrent-fetches" => {
+                    parsed.concurrent_fetches = number(flag, inline, &mut args)?
+                }
+                "--part-suffix" => parsed.part_suffix = value(flag, inline, &mut args)?,
+                "--hidden-parts" => parsed.hidden_parts = true,
+                "--trust-parts" => parsed.trust_parts = true,
+                "--preserve-mtime" => parsed.preserve_mtime = true,
+                "--log-file" => parsed.log_file = Some(value(flag, inline, &mut args)?),
+                "--parallel" => parsed.parallel = number(flag, inline, &mut args)?,
+                "--exec" => parsed.exec = Some(parse_command(&value(flag, inline, &mut args)?)?),
+                "-V" | "--version" => parsed.version = true,
+                "-h" | "--help" => parsed.help = true,
+                "-" => parsed.stdin = true,
+                flag if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+                _ => parsed.urls.push(arg),
+            }
+        }
+
+        if parsed.urls.is_empty() && !parsed.stdin && !parsed.help && !parsed.version {
+            if !stdin_piped {
+                return Err("no public urls given".to_string());
+            }
+            parsed.stdin = true;
+        }
+
+        parsed.validate()?;
+        Ok(parsed)
+    }
+
+    /// Rejects values that parse fine but make no sense, and options that
+    /// can't be combined.
+    fn validate(&self) -> Result<(), String> {
+        in_range("--parallel", self.parallel, 1, MAX_PARALLEL)?;
+        in_range(
+            "--concurrent-fetches",
+            self.concurrent_fetches,
+            1,
+            MAX_CONCURRENT_FETCHES,
+        )?;
+        if let Some(max_errors) = self.max_errors {
+            in_range("--max-errors", max_errors, 1, usize::MAX)?;
+        }
+
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size)
+            && min > max
+        {
+            return Err("--min-size is bigger than --max-size".to_string());
+        }
+        if self.part_suffix.contains('/') {
+            return Err("--part-suffix can't contain /".to_string());
+        }
+        if self.stdout && self.check_sizes {
+            return Err("--stdout and --check-sizes can't be used together".to_string());
+        }
+        if self.stdout && self.exec.is_some() {
+            return Err("--stdout and --exec can't be used together".to_string());
+        }
+        if self.stdout && self.preserve_mtime {
+            return Err("--stdout and --preserve-mtime can't be used together".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// The upper limits keep a stray digit from opening hundreds of connections.
+const MAX_PARALLEL: usize = 100;
+const MAX_CONCURRENT_FETCHES: usize = 32;
+
+fn in_range(flag: &str, value: usize, min: usize, max: usize) -> Result<(), String> {
+    if value < min {
+        Err(format!("{flag} must be at least {min}"))
+    } else if value > max {
+        Err(format!("{flag} can be at most {max}"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Takes the value of `flag`, either from `--flag=value` or the next argument.
+fn value(
+    flag: &str,
+    inline: Option<&str>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    match inline {
+        Some(value) => Ok(value.to_string()),
+        None => args.next().ok_or_else(|| format!("{flag} needs a value")),
+    }
+}
+
+/// Takes the value of `flag` and parses it as a number.
+fn number<T: std::str::FromStr>(
+    flag: &str,
+    inline: Option<&str>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<T, String> {
+    let value = value(flag, inline, args)?;
+    value
+        .parse()
+        .map_err(|_| format!("{flag} expects a number, got {value}"))
+}
+
+/// Takes the value of `flag` and parses it with [`parse_human_size`].
+fn size(
+    flag: &str,
+    inline: Option<&str>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<u64, String> {
+    parse_human_size(&value(flag, inline, args)?).map_err(|err| format!("{flag}: {err}"))
+}
+
+/// Size prefixes and the power of two they stand for.
+const SIZE_UNITS: [(&str, u32); 5] = [("", 0), ("k", 10), ("m", 20), ("g", 30), ("t", 40)];
+
+/// Parses sizes like `500k`, `1.5G`, `100MB` or `2 GiB` as bytes. Units are
+/// powers of 1024 whether or not they have the `i`, and case doesn't matter.
+pub fn parse_human_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    if number.is_empty() {
+        return Err(format!("{size:?} doesn't start with a number"));
+    }
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{number} is not a number"))?;
+
+    let unit = unit.trim().to_lowercase();
+    let prefix = unit.strip_suffix('b').unwrap_or(&unit);
+    let prefix = match prefix.strip_suffix('i') {
+        Some(prefix) if !prefix.is_empty() => prefix,
+        _ => prefix,
+    };
+    let (_, shift) = SIZE_UNITS
+        .iter()
+        .find(|(name, _)| *name == prefix)
+        .ok_or_else(|| format!("unknown unit {unit:?} in {size}, expected K, M, G or T"))?;
+
+    let bytes = number * (1u64 << shift) as f64;
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("{size} is too big"));
+    }
+    Ok(bytes as u64)
+}
+
+/// Splits an `--exec` command into words, rejecting unknown placeholders.
+fn parse_command(command: &str) -> Result<Vec<String>, String> {
+    let words: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+    if words.is_empty() {
+        return Err("--exec needs a command".to_string());
+    }
+
+    for word in &words {
+        check_placeholders("--exec", word, &["path", "name", "size"])?;
+    }
+
+    Ok(words)
+}
+
+/// Splits an `--ext` list, dropping leading dots and case.
+fn parse_extensions(list: &str) -> Result<Vec<String>, String> {
+    let extensions: Vec<String> = list
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect();
+    if extensions.is_empty() {
+        return Err("--ext needs at least one extension".to_string());
+    }
+
+    Ok(extensions)
+}
+
+/// Makes sure every `{placeholder}` in `text` is closed and one of `allowed`.
+fn check_placeholders(flag: &str, text: &str, allowed: &[&str]) -> Result<(), String> {
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("unclosed placeholder in {flag} {text}"));
+        };
+        let placeholder = &rest[start + 1..start + end];
+        if !allowed.contains(&placeholder) {
+            return Err(format!("unknown placeholder {{{placeholder}}} in {flag}"));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()), false)
+    }
+
+    #[test]
+    fn parses_verbosity() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(parse(&[url]).unwrap().verbosity, Verbosity::Normal);
+        assert_eq!(parse(&["-q", url]).unwrap().verbosity, Verbosity::Quiet);
+        assert_eq!(
+            parse(&[url, "--verbose"]).unwrap().verbosity,
+            Verbosity::Verbose
+        );
+        // the last one wins
+        assert_eq!(
+            parse(&["-v", "--quiet", url]).unwrap().verbosity,
+            Verbosity::Quiet
+        );
+    }
+
+    #[test]
+    fn needs_links_unless_piped() {
+        assert!(parse(&[]).is_err());
+        assert!(Args::parse(Vec::new(), true).unwrap().stdin);
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--bogus", "https://mega.nz/file/AbCd#k3y"]).is_err());
+    }
+
+    #[test]
+    fn parses_exec_commands() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(
+            parse(&["--exec", "unzip  -o {path}", url]).unwrap().exec,
+            Some(vec![
+                "unzip".to_string(),
+                "-o".to_string(),
+                "{path}".to_string()
+            ])
+        );
+        assert!(parse(&["--exec", " ", url]).is_err());
+        assert!(parse(&["--exec", "echo {date}", url]).is_err());
+    }
+
+    #[test]
+    fn checks_placeholders() {
+        let allowed = ["path", "name"];
+        assert!(check_placeholders("--exec", "no placeholders", &allowed).is_ok());
+        assert!(check_placeholders("--exec", "{path}.{name}", &allowed).is_ok());
+        assert!(check_placeholders("--exec", "{size}", &allowed).is_err());
+        assert!(check_placeholders("--exec", "{path", &allowed).is_err());
+        assert!(check_placeholders("--exec", "{}", &allowed).is_err());
+    }
+
+    #[test]
+    fn parses_path_templates() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(
+            parse(&["--path-template={root}/{name}", url])
+                .unwrap()
+                .path_template
+                .as_deref(),
+            Some("{root}/{name}")
+        );
+        // every file would land on the same path
+        assert!(parse(&["--path-template", "{root}/{ext}", url]).is_err());
+        assert!(parse(&["--path-template", "{size}/{name}", url]).is_err());
+    }
+
+    #[test]
+    fn parses_download_caps() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        let args = parse(&["--max-files", "10", "--max-total=1.5G", "-y", url]).unwrap();
+        assert_eq!(args.max_files, Some(10));
+        assert_eq!(args.max_total, Some(1536 * 1024 * 1024));
+        assert!(args.yes);
+        assert!(parse(&["--max-files", "ten", url]).is_err());
+        assert!(parse(&["--max-total", "lots", url]).is_err());
+        assert!(parse(&[url, "--max-files"]).is_err());
+    }
+
+    #[test]
+    fn parses_fetch_retries() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(parse(&[url]).unwrap().fetch_retries, 3);
+        assert_eq!(
+            parse(&["--fetch-retries", "0", url]).unwrap().fetch_retries,
+            0
+        );
+        assert!(parse(&["--fetch-retries", "-1", url]).is_err());
+    }
+
+    #[test]
+    fn limits_concurrent_fetches() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(parse(&[url]).unwrap().concurrent_fetches, 4);
+        assert_eq!(
+            parse(&["--concurrent-fetches=32", url])
+                .unwrap()
+                .concurrent_fetches,
+            32
+        );
+        assert!(parse(&["--concurrent-fetches", "0", url]).is_err());
+        assert!(parse(&["--concurrent-fetches", "33", url]).is_err());
+    }
+
+    #[test]
+    fn parses_part_options() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        let args = parse(&[url]).unwrap();
+        assert_eq!(
+            (args.part_suffix.as_str(), args.hidden_parts),
+            (".part", false)
+        );
+        let args = parse(&["--part-suffix=", "--hidden-parts", url]).unwrap();
+        assert_eq!((args.part_suffix.as_str(), args.hidden_parts), ("", true));
+        assert!(parse(&["--part-suffix", "/tmp", url]).is_err());
+    }
+
+    #[test]
+    fn limits_parallel_downloads() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(parse(&[url]).unwrap().parallel, 20);
+        assert_eq!(parse(&["--parallel", "1", url]).unwrap().parallel, 1);
+        assert_eq!(parse(&["--parallel", "100", url]).unwrap().parallel, 100);
+        assert!(parse(&["--parallel", "0", url]).is_err());
+        assert!(parse(&["--parallel", "101", url]).is_err());
+    }
+
+    #[test]
+    fn parses_fail_fast() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert!(!parse(&[url]).unwrap().fail_fast);
+        assert!(parse(&["--fail-fast", url]).unwrap().fail_fast);
+    }
+
+    #[test]
+    fn rejects_options_stdout_cant_use() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert!(parse(&["--stdout", url]).unwrap().stdout);
+        assert!(parse(&["--stdout", "--check-sizes", url]).is_err());
+        assert!(parse(&["--stdout", "--exec", "true", url]).is_err());
+        assert!(parse(&["--stdout", "--preserve-mtime", url]).is_err());
+    }
+
+    #[test]
+    fn needs_at_least_one_error() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(parse(&[url]).unwrap().max_errors, None);
+        assert_eq!(
+            parse(&["--max-errors", "1", url]).unwrap().max_errors,
+            Some(1)
+        );
+        assert!(parse(&["--max-errors", "0", url]).is_err());
+    }
+
+    #[test]
+    fn parses_extensions() {
+        assert_eq!(
+            parse_extensions(".mkv,,MP4"),
+            Ok(vec!["mkv".to_string(), "mp4".to_string()])
+        );
+        assert_eq!(parse_extensions(" mkv "), Ok(vec!["mkv".to_string()]));
+        assert!(parse_extensions(",.,").is_err());
+
+        let url = "https://mega.nz/file/AbCd#k3y";
+        assert_eq!(
+            parse(&["--ext", ".mkv,,MP4", url]).unwrap().extensions,
+            Some(vec!["mkv".to_string(), "mp4".to_string()])
+        );
+    }
+
+    #[test]
+    fn parses_size_filters() {
+        let url = "https://mega.nz/file/AbCd#k3y";
+        let args = parse(&["--min-size", "1M", "--max-size=1G", url]).unwrap();
+        assert_eq!(args.min_size, Some(1024 * 1024));
+        assert_eq!(args.max_size, Some(1024 * 1024 * 1024));
+        assert!(parse(&["--min-size", "1G", "--max-size", "1G", url]).is_ok());
+        assert!(parse(&["--min-size", "2G", "--max-size", "1G", url]).is_err());
+    }
+
+    #[test]
+    fn parses_human_sizes() {
+        assert_eq!(parse_human_size("500"), Ok(500));
+        assert_eq!(parse_human_size("500k"), Ok(500 * 1024));
+        assert_eq!(parse_human_size("1.5G"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_human_size("100MB"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_human_size(" 2 GiB "), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_human_size("1t"), Ok(1 << 40));
+        assert_eq!(parse_human_size("10b"), Ok(10));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        for size in ["", "k", "-1", "1.2.3", "5 xb", "5i", "1ki2", "99999999999T"] {
+            assert!(parse_human_size(size).is_err(), "{size}");
+        }
+    }
+
+    #[test]
+    fn parses_version() {
+        assert!(parse(&["-V"]).unwrap().version);
+        assert!(parse(&["--version"]).unwrap().version);
+        assert!(!parse(&["https://mega.nz/file/AbCd#k3y"]).unwrap().version);
+    }
+}
@@ -1,17 +1,49 @@
 #![feature(let_chains)]
 
-use std::{env, fs, path::PathBuf, time::Duration};
+mod args;
+mod url;
 
+use std::{
+    collections::HashSet,
+    env, fs,
+    future::Future,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use args::{Args, Progress, Verbosity};
 use async_read_progress::AsyncReadProgressExt;
 use console::style;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use tokio::fs::{create_dir_all, File};
-use tokio_util::compat::TokioAsyncWriteCompatExt;
+use futures::StreamExt;
+use indicatif::{
+    BinaryBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use tokio::{
+    fs::{create_dir_all, File, OpenOptions},
+    sync::Semaphore,
+};
+use tokio_util::{compat::TokioAsyncWriteCompatExt, sync::CancellationToken};
+use url::MegaUrl;
 
 fn get_all_paths<'node>(
     nodes: &'node mega::Nodes,
     node: &'node mega::Node,
+    template: Option<&PathTemplate>,
 ) -> Vec<(String, &'node mega::Node)> {
+    // a file link has no folders around it, so no {root} or {folder} either
+    if node.kind().is_file() {
+        let path = match template {
+            Some(template) => template.fill("", "", node.name()),
+            None => node.name().to_string(),
+        };
+        return vec![(path, node)];
+    }
+
     let mut paths = vec![];
     let (mut folders, mut files): (Vec<_>, Vec<_>) = node
         .children()
@@ -24,12 +56,18 @@ fn get_all_paths<'node>(
 
     let mut file_paths = files
         .iter()
-        .filter_map(|file| Some((build_path(node, nodes, file)?, *file)))
+        .filter_map(|file| {
+            let path = match template {
+                Some(template) => template.render(nodes, node, file),
+                None => build_path(node, nodes, file)?,
+            };
+            Some((path, *file))
+        })
         .collect();
 
     let mut child_file_paths: Vec<(String, &mega::Node)> = folders
         .iter()
-        .flat_map(|folder| get_all_paths(nodes, folder))
+        .flat_map(|folder| get_all_paths(nodes, folder, template))
         .collect();
 
     paths.append(&mut file_paths);
@@ -50,39 +88,836 @@ fn build_path(node: &mega::Node, nodes: &mega::Nodes, file: &mega::Node) -> Opti
     ))
 }
 
-async fn run(mega: &mega::Client, public_url: &str) -> mega::Result<()> {
-    let nodes = mega.fetch_public_nodes(public_url).await?;
+/// A `--path-template`, with the values that stay fixed for the whole run.
+struct PathTemplate {
+    template: String,
+    date: String,
+}
 
-    for root in nodes.roots() {
-        let paths: Vec<(String, &mega::Node)> = get_all_paths(&nodes, root)
-            .iter()
-            .filter_map(|(path, node)| {
-                let _dir = fs::create_dir_all(PathBuf::from(&path).parent().unwrap()).ok();
-                if let Ok(len) = fs::metadata(path)
-                    && len.len() == node.size()
-                {
-                    None
+impl PathTemplate {
+    fn new(template: String) -> Self {
+        PathTemplate {
+            template,
+            date: today(),
+        }
+    }
+
+    fn render(&self, nodes: &mega::Nodes, folder: &mega::Node, file: &mega::Node) -> String {
+        let mut ancestors = vec![folder];
+        while let Some(parent) = ancestors[ancestors.len() - 1]
+            .parent()
+            .and_then(|handle| nodes.get_node_by_handle(handle))
+        {
+            ancestors.push(parent);
+        }
+        let root = ancestors.pop().unwrap_or(folder);
+        let folders: Vec<&str> = ancestors.iter().rev().map(|node| node.name()).collect();
+        self.fill(root.name(), &folders.join("/"), file.name())
+    }
+
+    /// Renders the path of the file `name`, with `folders` between it and `root`.
+    fn fill(&self, root: &str, folders: &str, name: &str) -> String {
+        let ext = extension(name);
+        let path = fill_placeholders(&self.template, |placeholder| match placeholder {
+            "root" => Some(root),
+            "folder" => Some(folders),
+            "name" => Some(name),
+            "ext" => Some(ext),
+            "date" => Some(&self.date),
+            _ => None,
+        });
+
+        // empty placeholders would otherwise leave `a//b` or a leading `/` behind
+        let path = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join("/");
+        if self.template.starts_with('/') {
+            format!("/{path}")
+        } else {
+            path
+        }
+    }
+}
+
+/// Today's UTC date as `YYYY-MM-DD`.
+fn today() -> String {
+    date(unix_now() / 86_400)
+}
+
+/// The current UTC time as `YYYY-MM-DD HH:MM:SS`.
+fn timestamp() -> String {
+    let now = unix_now();
+    let secs = now % 86_400;
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        date(now / 86_400),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Formats days since the unix epoch as `YYYY-MM-DD`.
+fn date(days: u64) -> String {
+    // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// `--log-file` is moved aside when it grows past this.
+const LOG_ROTATE_ABOVE: u64 = 10 * 1024 * 1024;
+
+/// Appends timestamped lines to the `--log-file`.
+struct LogFile {
+    file: Mutex<fs::File>,
+}
+
+impl LogFile {
+    /// Opens `path` for appending, first moving it to `<path>.1` (replacing
+    /// any older one) if it's over [`LOG_ROTATE_ABOVE`].
+    fn open(path: &str) -> io::Result<Self> {
+        if fs::metadata(path).is_ok_and(|metadata| metadata.len() > LOG_ROTATE_ABOVE) {
+            fs::rename(path, format!("{path}.1"))?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(LogFile {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes a line, ignoring errors so a full disk doesn't stop downloads.
+    fn line(&self, message: &str) {
+        let _ = writeln!(self.file.lock().unwrap(), "{} {message}", timestamp());
+    }
+}
+
+/// How long a download may go without receiving any bytes before it is aborted.
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for a connection to a MEGA endpoint to be established.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay before the first retry of a failed fetch, doubling with each retry.
+const FETCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+struct Settings {
+    verbosity: Verbosity,
+    /// Resolved against the terminal, so never `None` unlike [`Args::progress`].
+    progress: Progress,
+    stall_timeout: Option<Duration>,
+    check_sizes: bool,
+    exec: Option<Vec<String>>,
+    path_template: Option<PathTemplate>,
+    anonymous: bool,
+    fetch_retries: u32,
+    concurrent_fetches: usize,
+    parallel: usize,
+    /// Stop at the first failure, see `--fail-fast`.
+    fail_fast: bool,
+    /// Stop starting downloads after this many have failed.
+    max_errors: Option<usize>,
+    /// Lowercase extensions from `--ext`, without dots.
+    extensions: Option<Vec<String>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Bytes a second to estimate download times with, from `--assume-speed`
+    /// or else remembered from earlier runs.
+    assumed_speed: Option<u64>,
+    log: Option<LogFile>,
+    part_suffix: String,
+    hidden_parts: bool,
+    trust_parts: bool,
+    /// Give downloaded files MEGA's modification time, see `--preserve-mtime`.
+    preserve_mtime: bool,
+}
+
+impl Settings {
+    /// Records `message` in the `--log-file`, if there is one.
+    fn log(&self, message: &str) {
+        if let Some(log) = &self.log {
+            log.line(message);
+        }
+    }
+}
+
+/// Exit status when some files failed to download.
+const EXIT_SOME_FAILED: i32 = 1;
+/// Exit status for bad command line arguments.
+const EXIT_USAGE: i32 = 2;
+/// Exit status when files failed and none were downloaded.
+const EXIT_ALL_FAILED: i32 = 3;
+/// Exit status when the download went over `--max-files`/`--max-total`
+/// without `--yes`, or wasn't confirmed at the prompt.
+const EXIT_DECLINED: i32 = 4;
+/// Exit status when logging in to MEGA failed.
+const EXIT_LOGIN_FAILED: i32 = 5;
+
+/// Interactive runs ask before downloading more than this.
+const CONFIRM_ABOVE: u64 = 10 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct Stats {
+    downloaded: usize,
+    downloaded_bytes: u64,
+    skipped: usize,
+    /// Files found the right size by `--check-sizes`.
+    checked: usize,
+    /// Files that failed to download, plus links that couldn't be fetched.
+    failed: usize,
+    /// Files left out for having the same path as one collected before.
+    duplicates: usize,
+    /// Files left out by `--ext`, `--min-size` or `--max-size`.
+    filtered: usize,
+    /// When each file downloaded, only recorded with `--timeline`.
+    timeline: Option<Vec<Interval>>,
+    /// The same counts for each link, in the order they were given.
+    links: Vec<(String, LinkCounts)>,
+}
+
+#[derive(Debug, Default)]
+struct LinkCounts {
+    downloaded: usize,
+    skipped: usize,
+    checked: usize,
+    /// Files that failed, or 1 if the link itself couldn't be fetched.
+    failed: usize,
+}
+
+#[derive(Debug)]
+struct Interval {
+    path: String,
+    start: Instant,
+    end: Instant,
+    bytes: u64,
+}
+
+impl Stats {
+    /// The counts for the files that came from `link`.
+    fn link(&mut self, link: &str) -> &mut LinkCounts {
+        let index = match self.links.iter().position(|(url, _)| url == link) {
+            Some(index) => index,
+            None => {
+                self.links.push((link.to_string(), LinkCounts::default()));
+                self.links.len() - 1
+            }
+        };
+        &mut self.links[index].1
+    }
+
+    /// With `strict`, files skipped for already being on disk count as failures.
+    fn exit_code(&self, strict: bool) -> i32 {
+        if self.failed > 0 && self.downloaded == 0 && self.checked == 0 {
+            EXIT_ALL_FAILED
+        } else if self.failed > 0 || (strict && self.skipped > 0) {
+            EXIT_SOME_FAILED
+        } else {
+            0
+        }
+    }
+}
+
+/// Fetches the nodes behind each link, `--concurrent-fetches` at a time.
+/// Links that can't be fetched are reported and counted as failed.
+async fn fetch_all(
+    mega: &mega::Client,
+    m: &MultiProgress,
+    links: &[MegaUrl],
+    settings: &Settings,
+    stats: &mut Stats,
+) -> Vec<(String, mega::Nodes)> {
+    // large folders can take a while, so show something is happening
+    let spinner = m.add(spinner(links.len() as u64, settings.progress));
+    let results: Vec<_> = futures::stream::iter(links)
+        .map(|link| {
+            let spinner = &spinner;
+            async move {
+                let public_url = link.to_string();
+                if settings.verbosity >= Verbosity::Verbose {
+                    m.suspend(|| println!("fetching {public_url}"));
+                }
+                let result = fetch_with_retries(mega, m, &public_url, settings).await;
+                spinner.inc(1);
+                (public_url, result)
+            }
+        })
+        .buffered(settings.concurrent_fetches)
+        .collect()
+        .await;
+    spinner.finish_and_clear();
+
+    let mut fetched = vec![];
+    for (public_url, result) in results {
+        match result {
+            Ok(nodes) => fetched.push((public_url, nodes)),
+            Err(err) => {
+                let hint = if settings.anonymous {
+                    " (it may need an account, try without --anonymous)"
                 } else {
-                    Some((path.clone(), *node))
+                    ""
+                };
+                m.suspend(|| eprintln!("{public_url} failed: {err}{hint}"));
+                settings.log(&format!("{public_url} failed: {err}"));
+                stats.failed += 1;
+                stats.link(&public_url).failed += 1;
+            }
+        }
+    }
+
+    fetched
+}
+
+/// A file behind one of the links, classified against what's on disk.
+struct CollectedFile<'node> {
+    /// The link the file was found behind.
+    link: &'node str,
+    path: String,
+    node: &'node mega::Node,
+    status: FileStatus,
+}
+
+/// Fetches the nodes behind `public_url`, retrying transient failures with
+/// exponential backoff.
+async fn fetch_with_retries(
+    mega: &mega::Client,
+    m: &MultiProgress,
+    public_url: &str,
+    settings: &Settings,
+) -> mega::Result<mega::Nodes> {
+    let fetch = || mega.fetch_public_nodes(public_url);
+    retry_fetch(m, public_url, settings, FETCH_RETRY_DELAY, fetch).await
+}
+
+/// Runs `fetch` for [`fetch_with_retries`], waiting `first_delay` before the
+/// first retry.
+async fn retry_fetch<T, F>(
+    m: &MultiProgress,
+    public_url: &str,
+    settings: &Settings,
+    first_delay: Duration,
+    fetch: impl Fn() -> F,
+) -> mega::Result<T>
+where
+    F: Future<Output = mega::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match fetch().await {
+            Err(err) if attempt < settings.fetch_retries && is_transient(&err) => {
+                // capped at a minute or so between attempts
+                let delay = first_delay * 2u32.pow(attempt.min(6));
+                attempt += 1;
+                if settings.verbosity >= Verbosity::Verbose {
+                    m.suspend(|| {
+                        println!(
+                            "fetching {public_url} failed ({err}), retry {attempt} of {} in {delay:?}",
+                            settings.fetch_retries
+                        )
+                    });
                 }
-            })
-            .collect();
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether fetching again might get past `err`. Anything else, like a
+/// malformed or taken down link, won't get any better.
+fn is_transient(err: &mega::Error) -> bool {
+    match err {
+        mega::Error::IoError(_) => true,
+        mega::Error::ReqwestError(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err.status().is_some_and(|status| status.is_server_error())
+        }
+        mega::Error::MegaError(code) => matches!(
+            code,
+            mega::ErrorCode::EAGAIN | mega::ErrorCode::ERATELIMIT | mega::ErrorCode::ETEMPUNAVAIL
+        ),
+        _ => false,
+    }
+}
+
+/// How many files are checked against the disk at once, which adds up on
+/// network mounts.
+const CLASSIFY_CONCURRENCY: usize = 32;
+
+/// Lists every file behind the fetched links, in download order. Files that
+/// would be saved to the same path, like when links overlap, are only listed
+/// once and counted as duplicates.
+async fn collect_files<'node>(
+    m: &MultiProgress,
+    fetched: &'node [(String, mega::Nodes)],
+    settings: &Settings,
+    stats: &mut Stats,
+) -> Vec<CollectedFile<'node>> {
+    let (paths, duplicates) = split_duplicates(fetched.iter().flat_map(|(link, nodes)| {
+        nodes
+            .roots()
+            .flat_map(|root| get_all_paths(nodes, root, settings.path_template.as_ref()))
+            .map(move |(path, node)| (link.as_str(), path, node))
+    }));
+    m.suspend(|| {
+        for (link, path, _) in &duplicates {
+            eprintln!("{path} from {link} is listed more than once, keeping the first");
+        }
+    });
+    stats.duplicates += duplicates.len();
+
+    futures::stream::iter(paths)
+        .map(|(link, path, node)| async move {
+            CollectedFile {
+                link,
+                status: file_status(&path, node, settings).await,
+                path,
+                node,
+            }
+        })
+        .buffered(CLASSIFY_CONCURRENCY)
+        .collect()
+        .await
+}
+
+/// A file as listed by a link, with the path it's saved to.
+type Listed<'link, T> = (&'link str, String, T);
+
+/// Splits the first file listed with each path from the ones after it.
+fn split_duplicates<'link, T>(
+    paths: impl IntoIterator<Item = Listed<'link, T>>,
+) -> (Vec<Listed<'link, T>>, Vec<Listed<'link, T>>) {
+    let mut seen = HashSet::new();
+    paths
+        .into_iter()
+        .partition(|(_, path, _)| seen.insert(path.clone()))
+}
+
+/// Leaves out the files the filter options don't want, counting them.
+fn filter_files<'node>(
+    m: &MultiProgress,
+    files: Vec<CollectedFile<'node>>,
+    settings: &Settings,
+    stats: &mut Stats,
+) -> Vec<CollectedFile<'node>> {
+    let (wanted, filtered): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|file| is_wanted(file.node.name(), file.node.size(), settings));
+    stats.filtered += filtered.len();
+
+    if settings.verbosity >= Verbosity::Verbose {
+        m.suspend(|| {
+            for file in &filtered {
+                println!("leaving out {}", file.path);
+            }
+        });
+    }
+
+    wanted
+}
+
+/// Whether the file `name`, `size` bytes long, gets past the filter options.
+fn is_wanted(name: &str, size: u64, settings: &Settings) -> bool {
+    settings.min_size.is_none_or(|min| size >= min)
+        && settings.max_size.is_none_or(|max| size <= max)
+        && settings
+            .extensions
+            .as_ref()
+            .is_none_or(|extensions| extensions.contains(&extension(name).to_lowercase()))
+}
+
+/// The extension of the file `name`, or nothing for files without one. That
+/// includes dotfiles like `.bashrc`, which are all name.
+fn extension(name: &str) -> &str {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+}
+
+/// Splits collected files into the ones still to download and the ones
+/// already complete, reporting (and counting) what gets skipped or replaced.
+fn partition_by_status<'node>(
+    m: &MultiProgress,
+    mut files: Vec<CollectedFile<'node>>,
+    settings: &Settings,
+    stats: &mut Stats,
+) -> Vec<CollectedFile<'node>> {
+    for file in &mut files {
+        promote_complete_part(m, &file.path, file.node.size(), &mut file.status, settings);
+    }
+
+    let (complete, to_download): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|file| file.status == FileStatus::Complete);
+    stats.skipped += complete.len();
+    for file in &complete {
+        stats.link(file.link).skipped += 1;
+    }
+
+    if settings.verbosity >= Verbosity::Verbose {
+        m.suspend(|| {
+            for file in &complete {
+                println!("skipping {}, already downloaded", file.path);
+            }
+            for file in &to_download {
+                match file.status {
+                    FileStatus::SizeMismatch(len) => println!(
+                        "replacing {}, {len} of {} bytes on disk",
+                        file.path,
+                        file.node.size()
+                    ),
+                    FileStatus::Partial(len) => println!(
+                        "restarting {}, {len} of {} bytes left from an earlier run",
+                        file.path,
+                        file.node.size()
+                    ),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    to_download
+}
 
-        let chunks: Vec<&[(String, &mega::Node)]> = paths.chunks(20).collect();
-        let m = MultiProgress::new();
+/// Files smaller than this may also use the fast lane slot.
+const SMALL_FILE: u64 = 16 * 1024 * 1024;
 
-        for chunk in chunks {
-            let mut futures = Vec::new();
+/// Downloads files `--parallel` at a time, plus one more small file, so a
+/// few huge files can't hold up everything else until they finish.
+async fn download_all(
+    mega: &mega::Client,
+    m: &MultiProgress,
+    files: &[CollectedFile<'_>],
+    settings: &Settings,
+    stats: &mut Stats,
+) {
+    let jobs: Vec<Job> = files
+        .iter()
+        .map(|file| Job {
+            link: file.link,
+            path: &file.path,
+            size: file.node.size(),
+        })
+        .collect();
+    let download = move |index: usize| {
+        let file = &files[index];
+        download_path(m, &file.path, file.node, mega, settings)
+    };
+    dispatch(m, &jobs, settings, stats, download).await;
+}
+
+/// A file for [`dispatch`] to schedule and report, without its node.
+struct Job<'a> {
+    link: &'a str,
+    path: &'a str,
+    size: u64,
+}
+
+/// Runs `download` for each of `jobs` by index, in the slots [`download_all`]
+/// describes, until `--fail-fast` or `--max-errors` stops it.
+async fn dispatch<F>(
+    m: &MultiProgress,
+    jobs: &[Job<'_>],
+    settings: &Settings,
+    stats: &mut Stats,
+    download: impl Fn(usize) -> F,
+) where
+    F: Future<Output = mega::Result<()>>,
+{
+    let slots = Semaphore::new(settings.parallel);
+    let fast_lane = Semaphore::new(1);
+    // set once no more downloads should start
+    let stopped = AtomicBool::new(false);
+    // only cancelled with --fail-fast
+    let cancel = CancellationToken::new();
+    // folders that only exist because of this run, which failed downloads can leave empty
+    let new_dirs: HashSet<PathBuf> = jobs
+        .iter()
+        .flat_map(|job| {
+            Path::new(job.path)
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .map(Path::to_path_buf)
+        })
+        .collect();
+
+    let mut results = futures::stream::iter(jobs.iter().enumerate())
+        .map(|(index, job)| {
+            let (slots, fast_lane, stopped, cancel) = (&slots, &fast_lane, &stopped, &cancel);
+            let download = &download;
+            async move {
+                let download = async {
+                    let _permit = if job.size < SMALL_FILE {
+                        tokio::select! {
+                            permit = slots.acquire() => permit,
+                            permit = fast_lane.acquire() => permit,
+                        }
+                    } else {
+                        slots.acquire().await
+                    }
+                    .expect("download slots closed");
+
+                    if stopped.load(Ordering::Relaxed) {
+                        return None;
+                    }
+                    let start = Instant::now();
+                    let result = download(index).await;
+                    Some((start, result))
+                };
 
-            for (path, node) in chunk {
-                futures.push(download_path(&m, path, node, mega));
+                let result = tokio::select! {
+                    result = download => result,
+                    () = cancel.cancelled() => None,
+                };
+                (job, result)
             }
+        })
+        .buffer_unordered(jobs.len().max(1));
 
-            futures::future::join_all(futures).await;
+    let mut unfinished = 0;
+    let mut errors = 0;
+    let mut reported_unwritable = false;
+    while let Some((&Job { link, path, size }, result)) = results.next().await {
+        let Some((start, result)) = result else {
+            unfinished += 1;
+            stats.link(link).failed += 1;
+            continue;
+        };
+        if result.is_err() {
+            errors += 1;
+            if settings.fail_fast {
+                // downloads waiting on the slot this one freed may be woken
+                // before the cancellation is seen, so stop them starting too
+                stopped.store(true, Ordering::Relaxed);
+                cancel.cancel();
+            }
+            if settings.max_errors.is_some_and(|max| errors >= max)
+                && !stopped.swap(true, Ordering::Relaxed)
+            {
+                m.suspend(|| eprintln!("{errors} downloads failed, not starting any more"));
+                settings.log(&format!("stopped after {errors} failed downloads"));
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                settings.log(&format!("{path} downloaded from {link}"));
+                stats.downloaded += 1;
+                stats.link(link).downloaded += 1;
+                stats.downloaded_bytes += size;
+                if let Some(timeline) = &mut stats.timeline {
+                    timeline.push(Interval {
+                        path: path.to_string(),
+                        start,
+                        end: Instant::now(),
+                        bytes: size,
+                    });
+                }
+            }
+            // downloads already running most likely fail the same way, so only say it once
+            Err(err) if is_unwritable(&err) => {
+                if !reported_unwritable {
+                    m.suspend(|| eprintln!("download directory unwritable: {err}"));
+                    settings.log(&format!("download directory unwritable: {err}"));
+                    reported_unwritable = true;
+                }
+                stopped.store(true, Ordering::Relaxed);
+                stats.failed += 1;
+                stats.link(link).failed += 1;
+            }
+            Err(err) => {
+                m.suspend(|| eprintln!("{path} failed: {err}"));
+                settings.log(&format!("{path} from {link} failed: {err}"));
+                stats.failed += 1;
+                stats.link(link).failed += 1;
+            }
         }
     }
 
-    Ok(())
+    if unfinished > 0 {
+        m.suspend(|| eprintln!("stopped before finishing the {unfinished} remaining files"));
+        stats.failed += unfinished;
+    }
+
+    // unfinished downloads remove their part files when dropped
+    drop(results);
+    clean_up(&new_dirs);
+}
+
+/// Removes any folder created this run that failed downloads left empty.
+fn clean_up(new_dirs: &HashSet<PathBuf>) {
+    let mut new_dirs: Vec<&PathBuf> = new_dirs.iter().collect();
+    // deepest first, so parents are already empty by the time they're tried
+    new_dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in new_dirs {
+        // fails, leaving the folder alone, unless it's empty
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+/// Whether `err` means nothing more can be written where files are saved,
+/// so every other download would fail the same way.
+fn is_unwritable(err: &mega::Error) -> bool {
+    let mega::Error::IoError(err) = err else {
+        return false;
+    };
+    matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull
+            | io::ErrorKind::ReadOnlyFilesystem
+            | io::ErrorKind::PermissionDenied
+    )
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FileStatus {
+    Complete,
+    /// On disk, but with a different size than the node.
+    SizeMismatch(u64),
+    /// Not on disk, but an unfinished download of it is, with this many bytes.
+    Partial(u64),
+    Missing,
+}
+
+async fn file_status(path: &str, node: &mega::Node, settings: &Settings) -> FileStatus {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.len() == node.size() => FileStatus::Complete,
+        Ok(metadata) => FileStatus::SizeMismatch(metadata.len()),
+        Err(_) => match tokio::fs::metadata(part_path(path, settings)).await {
+            Ok(metadata) => FileStatus::Partial(metadata.len()),
+            Err(_) => FileStatus::Missing,
+        },
+    }
+}
+
+/// With `--trust-parts`, finishes a download an earlier run got all the bytes
+/// of but was stopped before renaming. Only the size is checked, so otherwise
+/// the file is downloaded again.
+fn promote_complete_part(
+    m: &MultiProgress,
+    path: &str,
+    size: u64,
+    status: &mut FileStatus,
+    settings: &Settings,
+) {
+    if !settings.trust_parts || *status != FileStatus::Partial(size) {
+        return;
+    }
+
+    let part = part_path(path, settings);
+    match fs::rename(&part, path) {
+        Ok(()) => {
+            if settings.verbosity >= Verbosity::Verbose {
+                m.suspend(|| println!("{part} was already complete, renamed to {path}"));
+            }
+            *status = FileStatus::Complete;
+        }
+        // it just gets downloaded again
+        Err(err) => m.suspend(|| eprintln!("couldn't rename {part}: {err}")),
+    }
+}
+
+/// Sets the modification time of the file at `path`.
+fn set_modified(path: &str, modified: SystemTime) -> io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(modified)
+}
+
+/// A part file this run created, removed when dropped unless its download
+/// completed, so failed and cancelled downloads don't leave it behind.
+struct PartFile {
+    path: String,
+    keep: bool,
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where `path` is written until its download completes, following
+/// `--part-suffix` and `--hidden-parts`.
+fn part_path(path: &str, settings: &Settings) -> String {
+    let prefix = if settings.hidden_parts { "." } else { "" };
+    match path.rsplit_once('/') {
+        Some((dir, name)) => format!("{dir}/{prefix}{name}{}", settings.part_suffix),
+        None => format!("{prefix}{path}{}", settings.part_suffix),
+    }
+}
+
+/// Checks files already on disk are the size of their nodes, without downloading
+/// or reading them.
+fn check_sizes(
+    m: &MultiProgress,
+    files: &[CollectedFile<'_>],
+    settings: &Settings,
+    stats: &mut Stats,
+) {
+    for CollectedFile {
+        link,
+        path,
+        node,
+        status,
+    } in files
+    {
+        let failed = match *status {
+            FileStatus::Complete => {
+                if settings.verbosity >= Verbosity::Verbose {
+                    m.suspend(|| println!("{path} ok"));
+                }
+                stats.checked += 1;
+                stats.link(link).checked += 1;
+                false
+            }
+            FileStatus::SizeMismatch(len) => {
+                m.suspend(|| eprintln!("{path} is {len} bytes, expected {}", node.size()));
+                settings.log(&format!("{path} is {len} bytes, expected {}", node.size()));
+                true
+            }
+            FileStatus::Partial(len) => {
+                m.suspend(|| eprintln!("{path} is only partly downloaded, {len} bytes so far"));
+                settings.log(&format!("{path} is only partly downloaded"));
+                true
+            }
+            FileStatus::Missing => {
+                m.suspend(|| eprintln!("{path} is missing"));
+                settings.log(&format!("{path} is missing"));
+                true
+            }
+        };
+
+        if failed {
+            stats.failed += 1;
+            stats.link(link).failed += 1;
+        }
+    }
 }
 
 async fn download_path(
@@ -90,59 +925,755 @@ async fn download_path(
     path: &str,
     node: &mega::Node,
     mega: &mega::Client,
+    settings: &Settings,
 ) -> mega::Result<()> {
-    let (reader, writer) = sluice::pipe::pipe();
-
     create_dir_all(PathBuf::from(&path).parent().unwrap()).await?;
-    let file = File::create(&path).await?;
+    // something is already reading a named pipe, so it's written to directly
+    let fifo = is_fifo(path);
+    let (file, part) = if fifo {
+        (OpenOptions::new().write(true).open(path).await?, None)
+    } else {
+        let part = part_path(path, settings);
+        let file = File::create(&part).await?;
+        // with an empty --part-suffix the download goes straight to `path`
+        let part = (part != path).then_some(PartFile {
+            path: part,
+            keep: false,
+        });
+        (file, part)
+    };
 
-    let bar = m.add(progress_bar(node));
+    let bar = m.add(progress_bar(node, settings.progress));
     bar.set_message(format!("downloading {0}...", node.name()));
 
+    // a reader pausing a pipe isn't the download stalling
+    let stall_timeout = if fifo { None } else { settings.stall_timeout };
+    download_to(mega, node, file, &bar, stall_timeout).await?;
+    if let Some(mut part) = part {
+        tokio::fs::rename(&part.path, path).await?;
+        part.keep = true;
+    }
+    // files from before 1970 just keep the time they downloaded
+    if settings.preserve_mtime
+        && !fifo
+        && let Some(secs) = node
+            .modified_at()
+            .and_then(|modified| u64::try_from(modified.timestamp()).ok())
+        && let Err(err) = set_modified(path, UNIX_EPOCH + Duration::from_secs(secs))
+    {
+        m.suspend(|| eprintln!("couldn't set the modification time of {path}: {err}"));
+    }
+
+    bar.finish_with_message(format!("{0} downloaded !", node.name()));
+    if settings.progress == Progress::Off && settings.verbosity >= Verbosity::Normal {
+        println!("{path} downloaded");
+    }
+    if let Some(command) = &settings.exec {
+        run_hook(m, command, path, node.name(), node.size()).await;
+    }
+    Ok(())
+}
+
+/// Streams the single file behind the links to stdout for `--stdout`.
+async fn download_stdout(
+    mega: &mega::Client,
+    m: &MultiProgress,
+    files: &[CollectedFile<'_>],
+    settings: &Settings,
+    stats: &mut Stats,
+) {
+    let [file] = files else {
+        // a link that failed to fetch leaves nothing, which is already reported
+        // and exits as a failure rather than as misuse
+        if files.len() > 1 || stats.failed == 0 {
+            eprintln!(
+                "octo-dl: --stdout needs links to a single file, these have {}",
+                files.len()
+            );
+            std::process::exit(EXIT_USAGE);
+        }
+        return;
+    };
+
+    let bar = m.add(progress_bar(file.node, settings.progress));
+    bar.set_message(format!("downloading {0}...", file.node.name()));
+
+    // whatever reads stdout may pause it, which isn't the download stalling
+    let result = download_to(mega, file.node, tokio::io::stdout(), &bar, None).await;
+    match result {
+        Ok(()) => {
+            bar.finish_with_message(format!("{0} downloaded !", file.node.name()));
+            settings.log(&format!("{} streamed to stdout", file.path));
+            stats.downloaded += 1;
+            stats.link(file.link).downloaded += 1;
+            stats.downloaded_bytes += file.node.size();
+        }
+        Err(err) => {
+            m.suspend(|| eprintln!("{} failed: {err}", file.path));
+            settings.log(&format!("{} failed: {err}", file.path));
+            stats.failed += 1;
+            stats.link(file.link).failed += 1;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &str) -> bool {
+    false
+}
+
+/// Streams `node` into `writer`, reporting progress on `bar`. The bar is
+/// abandoned if the download stalls, but otherwise left for the caller to finish.
+async fn download_to<W>(
+    mega: &mega::Client,
+    node: &mega::Node,
+    writer: W,
+    bar: &ProgressBar,
+    stall_timeout: Option<Duration>,
+) -> mega::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let fill = |pipe_writer| mega.download_node(node, pipe_writer);
+    stream_to(node.name(), fill, writer, bar, stall_timeout).await
+}
+
+/// Streams what `fill` writes into its pipe on to `writer`, for [`download_to`].
+async fn stream_to<W, F>(
+    name: &str,
+    fill: impl FnOnce(sluice::pipe::PipeWriter) -> F,
+    writer: W,
+    bar: &ProgressBar,
+    stall_timeout: Option<Duration>,
+) -> mega::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+    F: Future<Output = mega::Result<()>>,
+{
+    let (reader, pipe_writer) = sluice::pipe::pipe();
+    let last_progress = Arc::new(Mutex::new(Instant::now()));
+
     let reader = {
         let bar = bar.clone();
+        let last_progress = last_progress.clone();
 
         reader.report_progress(Duration::from_millis(100), move |bytes_read| {
             bar.set_position(bytes_read as u64);
+            *last_progress.lock().unwrap() = Instant::now();
         })
     };
 
     let handle =
-        tokio::spawn(async move { futures::io::copy(reader, &mut file.compat_write()).await });
-    mega.download_node(node, writer).await?;
-    handle.await.expect("download failed")?;
-    bar.finish_with_message(format!("{0} downloaded !", node.name()));
-    Ok(())
+        tokio::spawn(async move { futures::io::copy(reader, &mut writer.compat_write()).await });
+    let download = async {
+        fill(pipe_writer).await?;
+        handle.await.expect("download failed")?;
+        Ok::<_, mega::Error>(())
+    };
+
+    tokio::select! {
+        result = download => result,
+        () = stalled(&last_progress, stall_timeout) => {
+            bar.abandon_with_message(format!("{name} stalled !"));
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "no data received for {name} in {0:?}",
+                    stall_timeout.unwrap_or_default()
+                ),
+            )
+            .into())
+        }
+    }
 }
 
-fn progress_bar(node: &mega::Node) -> ProgressBar {
+/// Runs the `--exec` command for the file `name`, `size` bytes long, downloaded
+/// to `path`. Failures are reported but don't fail the download.
+async fn run_hook(m: &MultiProgress, command: &[String], path: &str, name: &str, size: u64) {
+    let size = size.to_string();
+    let words: Vec<String> = command
+        .iter()
+        .map(|word| {
+            fill_placeholders(word, |placeholder| match placeholder {
+                "path" => Some(path),
+                "name" => Some(name),
+                "size" => Some(&size),
+                _ => None,
+            })
+        })
+        .collect();
+
+    match tokio::process::Command::new(&words[0])
+        .args(&words[1..])
+        .output()
+        .await
+    {
+        Ok(output) => m.suspend(|| {
+            print!("{}", String::from_utf8_lossy(&output.stdout));
+            eprint!("{}", String::from_utf8_lossy(&output.stderr));
+            if !output.status.success() {
+                eprintln!("{} for {path} exited with {}", words[0], output.status);
+            }
+        }),
+        Err(err) => m.suspend(|| eprintln!("couldn't run {} for {path}: {err}", words[0])),
+    }
+}
+
+/// Resolves once `timeout` has passed without `last_progress` being bumped.
+/// Never resolves when no timeout is set.
+async fn stalled(last_progress: &Mutex<Instant>, timeout: Option<Duration>) {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        let idle = last_progress.lock().unwrap().elapsed();
+        if idle >= timeout {
+            return;
+        }
+        tokio::time::sleep(timeout - idle).await;
+    }
+}
+
+fn progress_bar(node: &mega::Node, progress: Progress) -> ProgressBar {
     let bar = ProgressBar::new(node.size());
-    bar.set_style(progress_bar_style());
+    bar.set_style(progress_bar_style(progress));
     bar
 }
 
+/// Counts fetched links while they are fetched.
+fn spinner(links: u64, progress: Progress) -> ProgressBar {
+    let spinner = ProgressBar::new(links);
+    let style = ProgressStyle::default_spinner()
+        .template("{spinner} fetching links, {pos}/{len} done ({elapsed})")
+        .expect("somehow couldn't set up spinner template");
+    spinner.set_style(if progress == Progress::Ascii {
+        style.tick_chars("|/-\\ ")
+    } else {
+        style
+    });
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> mega::Result<()> {
-    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = match Args::parse(std::env::args().skip(1), !io::stdin().is_terminal()) {
+        Ok(args) if args.help => {
+            println!("{}", args::USAGE);
+            return Ok(());
+        }
+        Ok(args) if args.version => {
+            print_version();
+            return Ok(());
+        }
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("octo-dl: {err}\n\n{}", args::USAGE);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    let log = args.log_file.as_deref().map(|path| {
+        LogFile::open(path).unwrap_or_else(|err| {
+            eprintln!("octo-dl: couldn't open log file {path}: {err}");
+            std::process::exit(EXIT_USAGE);
+        })
+    });
 
-    assert!(args.len() != 0, "Usage: octo-dl <public url(s)>");
+    let (connect_timeout, request_timeout, stall_timeout) = match (
+        env_timeout("OCTO_CONNECT_TIMEOUT", Some(DEFAULT_CONNECT_TIMEOUT)),
+        env_timeout("OCTO_REQUEST_TIMEOUT", None),
+        env_timeout("OCTO_STALL_TIMEOUT", Some(DEFAULT_STALL_TIMEOUT)),
+    ) {
+        (Ok(connect), Ok(request), Ok(stall)) => (connect, request, stall),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            eprintln!("octo-dl: {err}");
+            std::process::exit(EXIT_USAGE);
+        }
+    };
 
-    let email = env::var("MEGA_EMAIL").expect("missing MEGA_EMAIL environment variable");
-    let password = env::var("MEGA_PASSWORD").expect("missing MEGA_PASSWORD environment variable");
-    let mfa = env::var("MEGA_MFA").ok();
+    let started = Instant::now();
+    let mut stats = Stats {
+        timeline: args.timeline.then(Vec::new),
+        ..Stats::default()
+    };
+    let links = parse_links(&args.urls, args.stdin, &mut stats);
+    if links.is_empty() {
+        std::process::exit(EXIT_ALL_FAILED);
+    }
 
-    let http_client = reqwest::Client::new();
+    let credentials = if args.anonymous {
+        None
+    } else {
+        match (env::var("MEGA_EMAIL"), env::var("MEGA_PASSWORD")) {
+            (Ok(email), Ok(password)) => Some((email, password, env::var("MEGA_MFA").ok())),
+            _ => {
+                eprintln!(
+                    "octo-dl: set MEGA_EMAIL and MEGA_PASSWORD, or pass --anonymous to download without an account"
+                );
+                std::process::exit(EXIT_USAGE);
+            }
+        }
+    };
+
+    let mut http_client = reqwest::Client::builder();
+    if let Some(timeout) = connect_timeout {
+        http_client = http_client.connect_timeout(timeout);
+    }
+    // off by default: it bounds whole requests, file bodies included
+    if let Some(timeout) = request_timeout {
+        http_client = http_client.timeout(timeout);
+    }
+    let http_client = http_client
+        .build()
+        .expect("couldn't set up the http client");
     let mut mega = mega::Client::builder().build(http_client)?;
 
-    mega.login(&email, &password, mfa.as_deref()).await.unwrap();
+    if let Some((email, password, mfa)) = &credentials {
+        // codes from a secret only last 30 seconds, so generate one at the last moment
+        let mfa = match (mfa, env::var("MEGA_MFA_SECRET")) {
+            (Some(code), _) => Some(code.clone()),
+            (None, Ok(secret)) => match totp_code(&secret) {
+                Ok(code) => Some(code),
+                Err(err) => {
+                    eprintln!("octo-dl: {err}");
+                    std::process::exit(EXIT_USAGE);
+                }
+            },
+            (None, Err(_)) => None,
+        };
+        if let Err(err) = mega.login(email, password, mfa.as_deref()).await {
+            eprintln!("octo-dl: login failed: {err}");
+            std::process::exit(EXIT_LOGIN_FAILED);
+        }
+    }
 
-    for public_url in args.as_slice() {
-        run(&mega, public_url).await?;
+    let dumb_term = env::var("TERM").is_ok_and(|term| term == "dumb");
+    if dumb_term || env::var_os("NO_COLOR").is_some() {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
     }
-    Ok(())
+
+    let progress = args.progress.unwrap_or_else(|| {
+        if console::Term::stderr().is_term() && !dumb_term {
+            Progress::Unicode
+        } else {
+            Progress::Off
+        }
+    });
+
+    let m = if args.verbosity == Verbosity::Quiet || progress == Progress::Off {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+
+    let settings = Settings {
+        // --verbose messages go to stdout
+        verbosity: if args.stdout {
+            args.verbosity.min(Verbosity::Normal)
+        } else {
+            args.verbosity
+        },
+        progress,
+        stall_timeout,
+        check_sizes: args.check_sizes,
+        exec: args.exec.clone(),
+        path_template: args.path_template.clone().map(PathTemplate::new),
+        anonymous: args.anonymous,
+        fetch_retries: args.fetch_retries,
+        concurrent_fetches: args.concurrent_fetches,
+        parallel: args.parallel,
+        fail_fast: args.fail_fast,
+        max_errors: args.max_errors,
+        extensions: args.extensions.clone(),
+        min_size: args.min_size,
+        max_size: args.max_size,
+        assumed_speed: args.assume_speed.or_else(historical_average_speed),
+        log,
+        part_suffix: args.part_suffix.clone(),
+        hidden_parts: args.hidden_parts,
+        trust_parts: args.trust_parts,
+        preserve_mtime: args.preserve_mtime,
+    };
+    settings.log(&format!("starting with {} links", links.len()));
+
+    stats.links = links
+        .iter()
+        .map(|link| (link.to_string(), LinkCounts::default()))
+        .collect();
+    let fetched = fetch_all(&mega, &m, &links, &settings, &mut stats).await;
+
+    let files = collect_files(&m, &fetched, &settings, &mut stats).await;
+    let files = filter_files(&m, files, &settings, &mut stats);
+    if settings.fail_fast && stats.failed > 0 {
+        eprintln!("octo-dl: not downloading anything, some links failed and --fail-fast is set");
+    } else if settings.check_sizes {
+        check_sizes(&m, &files, &settings, &mut stats);
+    } else if args.stdout {
+        download_stdout(&mega, &m, &files, &settings, &mut stats).await;
+    } else {
+        let files = partition_by_status(&m, files, &settings, &mut stats);
+
+        let total: u64 = files.iter().map(|file| file.node.size()).sum();
+        let over_limit = args.max_files.is_some_and(|max| files.len() > max)
+            || args.max_total.is_some_and(|max| total > max);
+        let batch = describe_batch(files.len(), total, settings.assumed_speed);
+        let ask = !args.yes && (over_limit || total > CONFIRM_ABOVE);
+        if ask && io::stdin().is_terminal() {
+            if !m.suspend(|| confirm(&format!("Download {batch}?"))) {
+                std::process::exit(EXIT_DECLINED);
+            }
+        } else if ask && over_limit {
+            eprintln!(
+                "octo-dl: {batch} is over the --max-files/--max-total limit, pass --yes to download them anyway"
+            );
+            std::process::exit(EXIT_DECLINED);
+        } else if settings.verbosity >= Verbosity::Normal && !files.is_empty() {
+            m.suspend(|| println!("downloading {batch}"));
+        }
+
+        let download_started = Instant::now();
+        download_all(&mega, &m, &files, &settings, &mut stats).await;
+        // a few small files say more about latency than bandwidth
+        if stats.downloaded_bytes >= MIN_SPEED_SAMPLE {
+            let secs = download_started.elapsed().as_secs_f64().max(0.001);
+            record_speed((stats.downloaded_bytes as f64 / secs) as u64);
+        }
+    }
+
+    settings.log(&format!(
+        "finished: {} downloaded, {} checked, {} skipped, {} failed",
+        stats.downloaded, stats.checked, stats.skipped, stats.failed
+    ));
+    // stdout only carries the file
+    if !args.stdout {
+        print_summary(&stats, started.elapsed(), settings.check_sizes);
+        if settings.verbosity >= Verbosity::Verbose && stats.links.len() > 1 {
+            print_links(&stats, settings.check_sizes);
+        }
+        if let Some(timeline) = &mut stats.timeline {
+            print_timeline(timeline, started);
+        }
+    }
+
+    match stats.exit_code(args.strict) {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
+}
+
+/// Describes what's about to be downloaded, like `3 files (1.5 GiB)`, with a
+/// rough estimate of how long it takes when a speed is known.
+fn describe_batch(files: usize, total: u64, speed: Option<u64>) -> String {
+    match speed.and_then(|speed| Some((speed, estimate(total, speed)?))) {
+        Some((speed, took)) => format!(
+            "{files} files ({}, about {} at {}/s)",
+            BinaryBytes(total),
+            HumanDuration(took),
+            BinaryBytes(speed)
+        ),
+        None => format!("{files} files ({})", BinaryBytes(total)),
+    }
+}
+
+/// Runs downloading less than this don't update the remembered speed.
+const MIN_SPEED_SAMPLE: u64 = 64 * 1024 * 1024;
+/// How much the latest run counts towards the remembered speed.
+const SPEED_WEIGHT: f64 = 0.3;
+
+/// Where the average download speed of earlier runs is kept.
+fn speed_file() -> Option<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+    Some(state.join("octo-dl").join("speed"))
+}
+
+/// The average download speed of earlier runs in bytes a second, if any
+/// were recorded.
+fn historical_average_speed() -> Option<u64> {
+    fs::read_to_string(speed_file()?).ok()?.trim().parse().ok()
+}
+
+/// Folds this run's speed into the remembered one, as a moving average so
+/// recent runs count most. It only feeds estimates, so errors are ignored.
+fn record_speed(speed: u64) {
+    let Some(path) = speed_file() else {
+        return;
+    };
+    let average = moving_average(historical_average_speed(), speed);
+
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = fs::write(path, average.to_string());
+}
+
+/// Weighs `speed` into the `old` average by [`SPEED_WEIGHT`].
+fn moving_average(old: Option<u64>, speed: u64) -> u64 {
+    match old {
+        Some(old) => (old as f64 * (1.0 - SPEED_WEIGHT) + speed as f64 * SPEED_WEIGHT) as u64,
+        None => speed,
+    }
+}
+
+/// How long `total` bytes take at `speed` bytes a second.
+fn estimate(total: u64, speed: u64) -> Option<Duration> {
+    (speed > 0).then(|| Duration::from_secs_f64(total as f64 / speed as f64))
+}
+
+/// Prints the version for bug reports, with the commit when built from git.
+fn print_version() {
+    print!("octo-dl {}", env!("CARGO_PKG_VERSION"));
+    if let Some(commit) = option_env!("OCTO_GIT_COMMIT") {
+        print!(" ({commit})");
+    }
+    println!();
+
+    // listed by build.rs
+    let features = env!("OCTO_FEATURES");
+    if features.is_empty() {
+        println!("features: none");
+    } else {
+        println!("features: {features}");
+    }
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    if io::stderr().flush().is_err() {
+        return false;
+    }
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Breaks the summary down by the link files came from.
+fn print_links(stats: &Stats, check_sizes: bool) {
+    println!("By link:");
+    for (link, counts) in &stats.links {
+        if check_sizes {
+            println!(
+                "  {link}: {} checked, {} failed",
+                counts.checked, counts.failed
+            );
+        } else {
+            println!(
+                "  {link}: {} downloaded, {} skipped, {} failed",
+                counts.downloaded, counts.skipped, counts.failed
+            );
+        }
+    }
+}
+
+fn print_timeline(timeline: &mut [Interval], started: Instant) {
+    timeline.sort_by_key(|interval| interval.start);
+
+    println!("Timeline:");
+    for interval in timeline.iter() {
+        let took = interval.end - interval.start;
+        println!(
+            "  {:>8.1?} .. {:>8.1?}  {:>12}/s  {}",
+            interval.start - started,
+            interval.end - started,
+            BinaryBytes((interval.bytes as f64 / took.as_secs_f64().max(0.001)) as u64),
+            interval.path
+        );
+    }
+    println!("Peak concurrent downloads: {}", peak_concurrency(timeline));
+}
+
+/// The most downloads that ran at once across `timeline`.
+fn peak_concurrency(timeline: &[Interval]) -> i32 {
+    let mut events: Vec<(Instant, i32)> = timeline
+        .iter()
+        .flat_map(|interval| [(interval.start, 1), (interval.end, -1)])
+        .collect();
+    // ends sort before starts at the same instant, so touching intervals don't overlap
+    events.sort();
+    events
+        .iter()
+        .scan(0, |running, (_, change)| {
+            *running += change;
+            Some(*running)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Pulls the MEGA links out of the command line, and stdin with `read_stdin`,
+/// reporting (and counting as failed) any argument that doesn't hold a valid one.
+fn parse_links(args: &[String], read_stdin: bool, stats: &mut Stats) -> Vec<MegaUrl> {
+    let mut urls = vec![];
+    for arg in args {
+        let found = url::extract_urls(arg);
+        if found.is_empty() {
+            eprintln!("{arg} doesn't contain a MEGA link");
+            stats.failed += 1;
+        }
+        urls.extend(found);
+    }
+
+    if read_stdin {
+        // piped input is usually a whole page or file, so only complain if none of it had a link
+        let found = read_links(io::stdin().lock());
+        if found.is_empty() {
+            eprintln!("stdin doesn't contain a MEGA link");
+            stats.failed += 1;
+        }
+        urls.extend(found);
+    }
+
+    url::dedup_urls(urls)
+        .into_iter()
+        .filter_map(|url| {
+            let link = MegaUrl::parse(&url);
+            if link.is_none() {
+                eprintln!("{url} is not a valid MEGA file or folder link");
+                stats.failed += 1;
+            }
+            link
+        })
+        .collect()
+}
+
+/// Pulls the MEGA links out of each line of `input`. Lines that aren't UTF-8,
+/// like the rest of a page saved in another encoding, are read lossily
+/// instead of ending the input there.
+fn read_links(mut input: impl BufRead) -> Vec<String> {
+    let mut links = vec![];
+    let mut line = vec![];
+    loop {
+        line.clear();
+        match input.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => links.extend(url::extract_urls(&String::from_utf8_lossy(&line))),
+            Err(err) => {
+                eprintln!("couldn't read stdin: {err}");
+                break;
+            }
+        }
+    }
+    links
+}
+
+fn print_summary(stats: &Stats, elapsed: Duration, check_sizes: bool) {
+    if check_sizes {
+        println!("Files checked:  {}", stats.checked);
+        println!("Files failed:   {}", stats.failed);
+        return;
+    }
+
+    println!(
+        "Files downloaded: {} ({} in {})",
+        stats.downloaded,
+        BinaryBytes(stats.downloaded_bytes),
+        HumanDuration(elapsed)
+    );
+    println!("Files skipped:    {}", stats.skipped);
+    println!("Files failed:     {}", stats.failed);
+    if stats.duplicates > 0 {
+        println!("Duplicates:       {}", stats.duplicates);
+    }
+    if stats.filtered > 0 {
+        println!("Files left out:   {}", stats.filtered);
+    }
+}
+
+/// Replaces each `{placeholder}` in `template` in a single pass, so values
+/// that happen to contain braces are left alone. Unknown placeholders are kept.
+fn fill_placeholders<'a>(template: &str, value: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        match rest
+            .find('}')
+            .and_then(|end| Some((end, value(&rest[1..end])?)))
+        {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+/// Generates the current 2FA code from a base32 `MEGA_MFA_SECRET`.
+#[cfg(feature = "totp")]
+fn totp_code(secret: &str) -> Result<String, String> {
+    totp_code_at(secret, unix_now())
+}
+
+/// Generates the 2FA code for `secret` at `time`, in seconds since the epoch.
+#[cfg(feature = "totp")]
+fn totp_code_at(secret: &str, time: u64) -> Result<String, String> {
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    // authenticator apps show secrets spaced out, lowercase or padded
+    let secret = secret.replace(' ', "");
+    let secret = Secret::Encoded(secret.trim_end_matches('=').to_uppercase())
+        .to_bytes()
+        .map_err(|_| "MEGA_MFA_SECRET is not valid base32".to_string())?;
+
+    Ok(TOTP::new_unchecked(Algorithm::SHA1, 6, 1, 30, secret).generate(time))
 }
 
-fn progress_bar_style() -> ProgressStyle {
+#[cfg(not(feature = "totp"))]
+fn totp_code(_secret: &str) -> Result<String, String> {
+    Err("MEGA_MFA_SECRET needs octo-dl to be built with the `totp` feature".to_string())
+}
+
+/// Reads a timeout in seconds from `var`, where `0` disables it.
+fn env_timeout(var: &str, default: Option<Duration>) -> Result<Option<Duration>, String> {
+    parse_timeout(var, env::var(var).ok(), default)
+}
+
+/// Parses the `value` of the timeout variable `var`, if it's set.
+fn parse_timeout(
+    var: &str,
+    value: Option<String>,
+    default: Option<Duration>,
+) -> Result<Option<Duration>, String> {
+    let Some(secs) = value else {
+        return Ok(default);
+    };
+    let secs: u64 = secs
+        .parse()
+        .map_err(|_| format!("{var} must be a number of seconds, not {secs}"))?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+fn progress_bar_style(progress: Progress) -> ProgressStyle {
+    if progress == Progress::Ascii {
+        return ProgressStyle::default_bar()
+            .progress_chars("=> ")
+            .template("[{bar:30}] {percent}% at {binary_bytes_per_sec} (ETA {eta}): {msg}")
+            .expect("somehow couldn't set up progress bar template");
+    }
+
     let template = format!(
         "{}{{bar:30.magenta.bold/magenta/bold}}{} {{percent}}% at {{binary_bytes_per_sec}} (ETA {{eta}}): {{msg}}",
         style("▐").bold().magenta(),
@@ -154,3 +1685,622 @@ fn progress_bar_style() -> ProgressStyle {
         .template(template.as_str())
         .expect("somehow couldn't set up progress bar template")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings as they are without any options.
+    fn settings() -> Settings {
+        Settings {
+            verbosity: Verbosity::Normal,
+            progress: Progress::Off,
+            stall_timeout: None,
+            check_sizes: false,
+            exec: None,
+            path_template: None,
+            anonymous: false,
+            fetch_retries: 3,
+            concurrent_fetches: 4,
+            parallel: 20,
+            fail_fast: false,
+            max_errors: None,
+            extensions: None,
+            min_size: None,
+            max_size: None,
+            assumed_speed: None,
+            log: None,
+            part_suffix: ".part".to_string(),
+            hidden_parts: false,
+            trust_parts: false,
+            preserve_mtime: false,
+        }
+    }
+
+    #[test]
+    fn parses_timeouts() {
+        let default = Some(Duration::from_secs(30));
+        let parse = |value: Option<&str>| {
+            parse_timeout("OCTO_STALL_TIMEOUT", value.map(str::to_string), default)
+        };
+        assert_eq!(parse(None), Ok(default));
+        assert_eq!(parse(Some("5")), Ok(Some(Duration::from_secs(5))));
+        assert_eq!(parse(Some("0")), Ok(None));
+        assert!(parse(Some("abc")).is_err());
+        assert!(parse(Some("-1")).is_err());
+    }
+
+    #[test]
+    fn appends_lines_to_the_log_file() {
+        let tmp = TempDir::new("log");
+        let path = tmp.0.join("octo-dl.log").to_string_lossy().into_owned();
+        LogFile::open(&path).unwrap().line("clip.mkv downloaded");
+        LogFile::open(&path).unwrap().line("song.mp3 downloaded");
+
+        let logged = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 2, "{logged}");
+        assert!(lines[0].ends_with(" clip.mkv downloaded"), "{logged}");
+        assert!(lines[1].ends_with(" song.mp3 downloaded"), "{logged}");
+    }
+
+    #[test]
+    fn rotates_large_log_files() {
+        let tmp = TempDir::new("log-rotate");
+        let path = tmp.0.join("octo-dl.log").to_string_lossy().into_owned();
+        let full = fs::File::create(&path).unwrap();
+        full.set_len(LOG_ROTATE_ABOVE + 1).unwrap();
+        drop(full);
+
+        LogFile::open(&path).unwrap().line("clip.mkv downloaded");
+
+        let rotated = fs::metadata(format!("{path}.1")).unwrap();
+        assert_eq!(rotated.len(), LOG_ROTATE_ABOVE + 1);
+        let logged = fs::read_to_string(&path).unwrap();
+        assert!(logged.ends_with(" clip.mkv downloaded\n"), "{logged}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_the_exec_command() {
+        let tmp = TempDir::new("exec");
+        let path = tmp.0.join("clip.mkv").to_string_lossy().into_owned();
+        let command = ["touch", "{path}.done", "{path}.{size}"].map(String::from);
+        run_hook(&MultiProgress::new(), &command, &path, "clip.mkv", 1024).await;
+
+        assert!(Path::new(&format!("{path}.done")).exists());
+        assert!(Path::new(&format!("{path}.1024")).exists());
+    }
+
+    #[tokio::test]
+    async fn streams_into_a_pipe() {
+        use futures::AsyncWriteExt;
+        use tokio::io::AsyncReadExt;
+
+        // a tiny buffer, so the stream waits on the reader like with a slow pipe
+        let (writer, mut reader) = tokio::io::duplex(4);
+        let fill = |mut pipe: sluice::pipe::PipeWriter| async move {
+            pipe.write_all(b"a small file").await?;
+            Ok(())
+        };
+        let bar = ProgressBar::hidden();
+        let mut read = vec![];
+        let (streamed, _) = tokio::join!(
+            stream_to("clip.mkv", fill, writer, &bar, None),
+            reader.read_to_end(&mut read),
+        );
+        streamed.unwrap();
+        assert_eq!(read, b"a small file");
+    }
+
+    #[test]
+    fn promotes_only_full_size_part_files() {
+        let tmp = TempDir::new("trust-parts");
+        let path = |name: &str| tmp.0.join(name).to_string_lossy().into_owned();
+        let settings = Settings {
+            trust_parts: true,
+            ..settings()
+        };
+        fs::write(path("full.mkv.part"), "1234").unwrap();
+        fs::write(path("short.mkv.part"), "12").unwrap();
+
+        let mut status = FileStatus::Partial(4);
+        promote_complete_part(&hidden(), &path("full.mkv"), 4, &mut status, &settings);
+        assert_eq!(status, FileStatus::Complete);
+        assert_eq!(fs::read_to_string(path("full.mkv")).unwrap(), "1234");
+        assert!(!Path::new(&path("full.mkv.part")).exists());
+
+        let mut status = FileStatus::Partial(2);
+        promote_complete_part(&hidden(), &path("short.mkv"), 4, &mut status, &settings);
+        assert_eq!(status, FileStatus::Partial(2));
+        assert!(Path::new(&path("short.mkv.part")).exists());
+        assert!(!Path::new(&path("short.mkv")).exists());
+    }
+
+    #[test]
+    fn sets_modification_times() {
+        let tmp = TempDir::new("mtime");
+        let path = tmp.0.join("clip.mkv").to_string_lossy().into_owned();
+        fs::write(&path, "done").unwrap();
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        set_modified(&path, modified).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    }
+
+    #[tokio::test]
+    async fn stalls_once_progress_stops() {
+        let last_progress = Mutex::new(Instant::now());
+        let timeout = Duration::from_millis(50);
+        let started = Instant::now();
+        stalled(&last_progress, Some(timeout)).await;
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn never_stalls_without_a_timeout() {
+        let last_progress = Mutex::new(Instant::now());
+        let never = stalled(&last_progress, None);
+        assert!(tokio::time::timeout(Duration::from_millis(50), never)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let stats = |downloaded, skipped, failed| Stats {
+            downloaded,
+            skipped,
+            failed,
+            ..Stats::default()
+        };
+        assert_eq!(stats(2, 0, 0).exit_code(false), 0);
+        assert_eq!(stats(2, 1, 0).exit_code(false), 0);
+        assert_eq!(stats(2, 1, 0).exit_code(true), EXIT_SOME_FAILED);
+        assert_eq!(stats(0, 1, 0).exit_code(true), EXIT_SOME_FAILED);
+        assert_eq!(stats(2, 0, 1).exit_code(false), EXIT_SOME_FAILED);
+        assert_eq!(stats(0, 1, 1).exit_code(false), EXIT_ALL_FAILED);
+        assert_eq!(stats(0, 0, 1).exit_code(true), EXIT_ALL_FAILED);
+
+        let checked = Stats {
+            checked: 1,
+            ..stats(0, 0, 1)
+        };
+        assert_eq!(checked.exit_code(false), EXIT_SOME_FAILED);
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let value = |placeholder: &str| match placeholder {
+            "name" => Some("a {name}.zip"),
+            "empty" => Some(""),
+            _ => None,
+        };
+        assert_eq!(fill_placeholders("x/{name}", value), "x/a {name}.zip");
+        assert_eq!(
+            fill_placeholders("{empty}{name}{empty}", value),
+            "a {name}.zip"
+        );
+        // unknown and unclosed placeholders are kept
+        assert_eq!(fill_placeholders("{size} {name", value), "{size} {name");
+        assert_eq!(fill_placeholders("{{name}}", value), "{a {name}.zip}");
+    }
+
+    #[test]
+    fn renders_path_templates() {
+        let template = |template: &str| PathTemplate {
+            template: template.to_string(),
+            date: "2024-01-31".to_string(),
+        };
+        assert_eq!(
+            template("{date}/{root}/{folder}/{name}").fill("Share", "a/b", "clip.mkv"),
+            "2024-01-31/Share/a/b/clip.mkv"
+        );
+        // files right in the shared folder have no {folder}
+        assert_eq!(
+            template("{root}/{folder}/{name}").fill("Share", "", "clip.mkv"),
+            "Share/clip.mkv"
+        );
+        assert_eq!(
+            template("/media/{ext}/{name}").fill("Share", "", "clip.mkv"),
+            "/media/mkv/clip.mkv"
+        );
+        assert_eq!(
+            template("{ext}/{name}").fill("Share", "", "README"),
+            "README"
+        );
+        assert_eq!(
+            template("{ext}/{name}").fill("Share", "", ".bashrc"),
+            ".bashrc"
+        );
+        assert_eq!(
+            template("//media/{folder}/{name}").fill("Share", "", "README"),
+            "/media/README"
+        );
+    }
+
+    #[cfg(feature = "totp")]
+    #[test]
+    fn reads_secrets_as_apps_show_them() {
+        // the SHA-1 test vectors from RFC 6238, cut down to 6 digits
+        let rfc = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        assert_eq!(totp_code_at(rfc, 59), Ok("287082".to_string()));
+        assert_eq!(totp_code_at(rfc, 1111111109), Ok("081804".to_string()));
+
+        let spaced = "gezd gnbv gy3t qojq gezd gnbv gy3t qojq";
+        assert_eq!(totp_code_at(spaced, 59), Ok("287082".to_string()));
+        assert_eq!(totp_code_at("mzxw 6===", 59), Ok("398408".to_string()));
+        assert!(totp_code_at("not base32!", 59).is_err());
+        assert!(totp_code("JBSWY3DPEHPK3PXP").is_ok());
+    }
+
+    #[test]
+    fn renders_file_links_without_folders() {
+        let template = PathTemplate {
+            template: "{root}/{folder}/{name}".to_string(),
+            date: "2024-01-31".to_string(),
+        };
+        assert_eq!(template.fill("", "", "clip.mkv"), "clip.mkv");
+    }
+
+    #[test]
+    fn filters_on_size_bounds() {
+        let settings = Settings {
+            min_size: Some(100),
+            max_size: Some(200),
+            ..settings()
+        };
+        for (size, wanted) in [
+            (99, false),
+            (100, true),
+            (101, true),
+            (199, true),
+            (200, true),
+            (201, false),
+        ] {
+            assert_eq!(is_wanted("clip.mkv", size, &settings), wanted, "{size}");
+        }
+    }
+
+    #[test]
+    fn filters_on_extensions() {
+        let settings = Settings {
+            extensions: Some(vec!["mkv".to_string(), "mp4".to_string()]),
+            ..settings()
+        };
+        for (name, wanted) in [
+            ("clip.mkv", true),
+            ("a.MKV", true),
+            ("clip.mkv.part", false),
+            ("README", false),
+            (".bashrc", false),
+            (".mkv", false),
+        ] {
+            assert_eq!(is_wanted(name, 1, &settings), wanted, "{name}");
+        }
+    }
+
+    /// Jobs of the given sizes, all from the same link.
+    fn jobs(sizes: &[u64]) -> Vec<Job<'static>> {
+        sizes
+            .iter()
+            .map(|&size| Job {
+                link: "https://mega.nz/folder/AbCd#k3y",
+                path: "clip.mkv",
+                size,
+            })
+            .collect()
+    }
+
+    fn hidden() -> MultiProgress {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    }
+
+    #[tokio::test]
+    async fn small_files_pass_big_ones() {
+        let settings = Settings {
+            parallel: 1,
+            ..settings()
+        };
+        let finished = Mutex::new(vec![]);
+        let small_done = tokio::sync::Notify::new();
+        let download = |index: usize| {
+            let (finished, small_done) = (&finished, &small_done);
+            async move {
+                // the big file holds the only slot until the small one is done
+                if index == 0 {
+                    small_done.notified().await;
+                } else {
+                    small_done.notify_one();
+                }
+                finished.lock().unwrap().push(index);
+                Ok(())
+            }
+        };
+
+        let mut stats = Stats::default();
+        let jobs = jobs(&[SMALL_FILE, SMALL_FILE - 1]);
+        let m = hidden();
+        let dispatched = dispatch(&m, &jobs, &settings, &mut stats, download);
+        tokio::time::timeout(Duration::from_secs(5), dispatched)
+            .await
+            .expect("the small file waited for the big one");
+        assert_eq!(*finished.lock().unwrap(), [1, 0]);
+        assert_eq!(stats.downloaded, 2);
+    }
+
+    #[tokio::test]
+    async fn fail_fast_stops_at_the_first_failure() {
+        let settings = Settings {
+            parallel: 1,
+            fail_fast: true,
+            ..settings()
+        };
+        let started = Mutex::new(vec![]);
+        let download = |index: usize| {
+            let started = &started;
+            async move {
+                started.lock().unwrap().push(index);
+                Err(io::Error::other("broken").into())
+            }
+        };
+
+        let mut stats = Stats::default();
+        let jobs = jobs(&[SMALL_FILE; 4]);
+        dispatch(&hidden(), &jobs, &settings, &mut stats, download).await;
+        assert_eq!(*started.lock().unwrap(), [0]);
+        assert_eq!((stats.downloaded, stats.failed), (0, 4));
+    }
+
+    #[tokio::test]
+    async fn stops_starting_downloads_after_max_errors() {
+        let settings = Settings {
+            parallel: 1,
+            max_errors: Some(2),
+            ..settings()
+        };
+        let started = Mutex::new(vec![]);
+        let download = |index: usize| {
+            let started = &started;
+            async move {
+                started.lock().unwrap().push(index);
+                Err(io::Error::other("broken").into())
+            }
+        };
+
+        let mut stats = Stats::default();
+        let jobs = jobs(&[SMALL_FILE; 5]);
+        dispatch(&hidden(), &jobs, &settings, &mut stats, download).await;
+        assert_eq!(*started.lock().unwrap(), [0, 1]);
+        // the three that never started count as failed too
+        assert_eq!((stats.downloaded, stats.failed), (0, 5));
+        assert_eq!(stats.links[0].1.failed, 5);
+    }
+
+    #[test]
+    fn spots_unwritable_download_dirs() {
+        for (kind, unwritable) in [
+            (io::ErrorKind::StorageFull, true),
+            (io::ErrorKind::ReadOnlyFilesystem, true),
+            (io::ErrorKind::PermissionDenied, true),
+            (io::ErrorKind::ConnectionReset, false),
+        ] {
+            let err = mega::Error::IoError(io::Error::from(kind));
+            assert_eq!(is_unwritable(&err), unwritable, "{kind:?}");
+        }
+        assert!(!is_unwritable(&mega::Error::InvalidPublicUrlFormat));
+    }
+
+    #[test]
+    fn finds_peak_concurrency() {
+        let started = Instant::now();
+        let interval = |start, end| Interval {
+            path: "clip.mkv".to_string(),
+            start: started + Duration::from_secs(start),
+            end: started + Duration::from_secs(end),
+            bytes: 0,
+        };
+        assert_eq!(peak_concurrency(&[]), 0);
+        // one starting as another ends doesn't count as both at once
+        assert_eq!(peak_concurrency(&[interval(0, 2), interval(2, 4)]), 1);
+        assert_eq!(peak_concurrency(&[interval(0, 3), interval(2, 4)]), 2);
+        assert_eq!(
+            peak_concurrency(&[
+                interval(0, 10),
+                interval(1, 2),
+                interval(1, 3),
+                interval(3, 4)
+            ]),
+            3
+        );
+    }
+
+    #[test]
+    fn names_part_files() {
+        assert_eq!(part_path("a/b/clip.mkv", &settings()), "a/b/clip.mkv.part");
+        assert_eq!(part_path("clip.mkv", &settings()), "clip.mkv.part");
+
+        let hidden = Settings {
+            hidden_parts: true,
+            ..settings()
+        };
+        assert_eq!(part_path("a/b/clip.mkv", &hidden), "a/b/.clip.mkv.part");
+        assert_eq!(part_path("clip.mkv", &hidden), ".clip.mkv.part");
+
+        let in_place = Settings {
+            part_suffix: String::new(),
+            ..settings()
+        };
+        assert_eq!(part_path("a/clip.mkv", &in_place), "a/clip.mkv");
+        let hidden_in_place = Settings {
+            hidden_parts: true,
+            ..in_place
+        };
+        assert_eq!(part_path("a/clip.mkv", &hidden_in_place), "a/.clip.mkv");
+    }
+
+    /// A fresh directory for one test, removed when it's dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("octo-dl-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn removes_only_empty_new_dirs() {
+        let tmp = TempDir::new("clean-up");
+        let kept = tmp.0.join("a/kept");
+        let empty = tmp.0.join("a/empty/deeper");
+        fs::create_dir_all(&kept).unwrap();
+        fs::create_dir_all(&empty).unwrap();
+        fs::write(kept.join("file"), "done").unwrap();
+
+        let new_dirs = [
+            tmp.0.join("a"),
+            kept.clone(),
+            tmp.0.join("a/empty"),
+            empty.clone(),
+        ];
+        clean_up(&new_dirs.into_iter().collect());
+
+        assert!(kept.join("file").exists());
+        assert!(!tmp.0.join("a/empty").exists());
+    }
+
+    #[test]
+    fn splits_duplicate_paths() {
+        let (firsts, duplicates) = split_duplicates([
+            ("link1", "a/1".to_string(), 1),
+            ("link1", "a/2".to_string(), 2),
+            ("link2", "a/1".to_string(), 3),
+            ("link2", "A/1".to_string(), 4),
+        ]);
+        assert_eq!(
+            firsts,
+            [
+                ("link1", "a/1".to_string(), 1),
+                ("link1", "a/2".to_string(), 2),
+                ("link2", "A/1".to_string(), 4),
+            ]
+        );
+        assert_eq!(duplicates, [("link2", "a/1".to_string(), 3)]);
+    }
+
+    #[test]
+    fn removes_part_files_unless_kept() {
+        let tmp = TempDir::new("part-file");
+        let path = |name: &str| tmp.0.join(name).to_string_lossy().into_owned();
+        fs::write(path("failed.part"), "half").unwrap();
+        fs::write(path("done.part"), "all").unwrap();
+
+        drop(PartFile {
+            path: path("failed.part"),
+            keep: false,
+        });
+        drop(PartFile {
+            path: path("done.part"),
+            keep: true,
+        });
+
+        assert!(!Path::new(&path("failed.part")).exists());
+        assert!(Path::new(&path("done.part")).exists());
+    }
+
+    #[tokio::test]
+    async fn retries_fetches_that_fail_transiently() {
+        use std::sync::atomic::AtomicUsize;
+
+        let attempts = AtomicUsize::new(0);
+        let fetch = || {
+            let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
+            async move {
+                if attempt < 3 {
+                    Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+                } else {
+                    Ok(attempt)
+                }
+            }
+        };
+        let url = "https://mega.nz/folder/AbCd#k3y";
+        let fetched = retry_fetch(&hidden(), url, &settings(), Duration::ZERO, fetch).await;
+        assert_eq!(fetched.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_fetches_that_fail_for_good() {
+        use std::sync::atomic::AtomicUsize;
+
+        let attempts = AtomicUsize::new(0);
+        let fetch = || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async { mega::Result::<()>::Err(mega::Error::MegaError(mega::ErrorCode::ENOENT)) }
+        };
+        let url = "https://mega.nz/folder/AbCd#k3y";
+        let fetched = retry_fetch(&hidden(), url, &settings(), Duration::ZERO, fetch).await;
+        assert!(fetched.is_err());
+        assert_eq!(attempts.into_inner(), 1);
+
+        // transient failures still give up once the retries run out
+        let attempts = AtomicUsize::new(0);
+        let fetch = || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async { mega::Result::<()>::Err(io::Error::from(io::ErrorKind::TimedOut).into()) }
+        };
+        let fetched = retry_fetch(&hidden(), url, &settings(), Duration::ZERO, fetch).await;
+        assert!(fetched.is_err());
+        assert_eq!(attempts.into_inner(), 4);
+    }
+
+    #[test]
+    fn retries_transient_errors_only() {
+        let io = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert!(is_transient(&mega::Error::IoError(io)));
+        assert!(is_transient(&mega::Error::MegaError(
+            mega::ErrorCode::ERATELIMIT
+        )));
+        assert!(!is_transient(&mega::Error::MegaError(
+            mega::ErrorCode::ENOENT
+        )));
+        assert!(!is_transient(&mega::Error::InvalidPublicUrlFormat));
+    }
+
+    #[test]
+    fn reads_links_past_lines_that_arent_utf8() {
+        let input = b"caf\xe9 https://mega.nz/file/AbCd#k3y\n\xff\nhttps://mega.nz/folder/EfGh#k3y";
+        assert_eq!(
+            read_links(io::Cursor::new(input)),
+            [
+                "https://mega.nz/file/AbCd#k3y",
+                "https://mega.nz/folder/EfGh#k3y"
+            ]
+        );
+    }
+
+    #[test]
+    fn estimates_download_time() {
+        assert_eq!(estimate(3000, 1000), Some(Duration::from_secs(3)));
+        assert_eq!(estimate(3000, 0), None);
+        assert_eq!(describe_batch(2, 2048, None), "2 files (2.00 KiB)");
+        assert_eq!(describe_batch(2, 2048, Some(0)), "2 files (2.00 KiB)");
+        assert!(describe_batch(2, 2048, Some(1024)).starts_with("2 files (2.00 KiB, about "));
+    }
+
+    #[test]
+    fn averages_speeds() {
+        assert_eq!(moving_average(None, 1000), 1000);
+        assert_eq!(moving_average(Some(1000), 1000), 1000);
+        assert_eq!(moving_average(Some(1000), 2000), 1300);
+        assert_eq!(moving_average(Some(2000), 0), 1400);
+    }
+}
@@ -0,0 +1,439 @@
+use std::{collections::HashSet, fmt};
+
+const HOSTS: [&str; 2] = ["mega.nz/", "mega.co.nz/"];
+
+/// A MEGA file or folder link, split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MegaUrl {
+    File {
+        handle: String,
+        key: String,
+    },
+    Folder {
+        handle: String,
+        key: String,
+        /// A file or folder inside the shared folder, e.g. `folder/AbCdEfGh`.
+        subpath: Option<String>,
+    },
+}
+
+impl MegaUrl {
+    /// Parses modern and legacy MEGA links, returning `None` for anything
+    /// that isn't a well formed file or folder link.
+    pub fn parse(url: &str) -> Option<Self> {
+        let normalized = normalize_mega_url(url);
+        let rest = normalized.strip_prefix("https://mega.nz/")?;
+        let (kind, rest) = rest.split_once('/')?;
+        let (handle, fragment) = rest.split_once('#')?;
+
+        let (key, subpath) = match fragment.split_once('/') {
+            Some((key, subpath)) => (key, Some(subpath)),
+            None => (fragment, None),
+        };
+
+        if !is_base64(handle) || !is_base64(key) {
+            return None;
+        }
+
+        let (handle, key) = (handle.to_string(), key.to_string());
+        match (kind, subpath) {
+            ("file", None) => Some(MegaUrl::File { handle, key }),
+            ("folder", None) => Some(MegaUrl::Folder {
+                handle,
+                key,
+                subpath: None,
+            }),
+            ("folder", Some(subpath)) => {
+                let (child_kind, child) = subpath.split_once('/')?;
+                if !matches!(child_kind, "file" | "folder") || !is_base64(child) {
+                    return None;
+                }
+                Some(MegaUrl::Folder {
+                    handle,
+                    key,
+                    subpath: Some(subpath.to_string()),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MegaUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MegaUrl::File { handle, key } => write!(f, "https://mega.nz/file/{handle}#{key}"),
+            MegaUrl::Folder {
+                handle,
+                key,
+                subpath: None,
+            } => write!(f, "https://mega.nz/folder/{handle}#{key}"),
+            MegaUrl::Folder {
+                handle,
+                key,
+                subpath: Some(subpath),
+            } => write!(f, "https://mega.nz/folder/{handle}#{key}/{subpath}"),
+        }
+    }
+}
+
+/// Handles and keys are unpadded url-safe base64.
+fn is_base64(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// How many layers of percent-encoding [`extract_urls`] peels off looking for links.
+const MAX_DECODE_DEPTH: usize = 3;
+
+/// Finds every MEGA link in `text` and returns it normalized, without repeats.
+///
+/// Links percent-encoded into another URL, like the
+/// `out.php?url=https%3A%2F%2Fmega.nz%2F...` redirectors forums use, are
+/// decoded and found as well.
+pub fn extract_urls(text: &str) -> Vec<String> {
+    let mut urls = vec![];
+    collect_urls(text, MAX_DECODE_DEPTH, &mut urls);
+    dedup_urls(urls)
+}
+
+/// Drops repeated links, keeping the first occurrence of each.
+///
+/// Links are compared normalized and ignoring case. Handles are technically
+/// case sensitive, but two links in one batch that differ only by case are
+/// much more likely to be one link mangled than two distinct shares.
+pub fn dedup_urls(urls: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    urls.into_iter()
+        .map(|url| normalize_mega_url(&url))
+        .filter(|url| seen.insert(url.to_lowercase()))
+        .collect()
+}
+
+/// Punctuation that ends a sentence rather than a link.
+const TRAILING: [char; 6] = ['.', ',', ';', ':', '!', '?'];
+
+fn collect_urls(text: &str, depth: usize, urls: &mut Vec<String>) {
+    let mut tokens = text
+        .split(|c: char| c.is_whitespace() || "\"'<>()[]{}".contains(c))
+        .filter(|token| !token.is_empty())
+        .peekable();
+
+    while let Some(token) = tokens.next() {
+        let found = urls.len();
+
+        // query parameters can each carry a link of their own
+        for candidate in token.split('&') {
+            if let Some(start) = find_host(candidate) {
+                let link = candidate[start..].trim_end_matches(TRAILING);
+                urls.push(normalize_mega_url(link));
+            }
+        }
+
+        // long links get wrapped, leaving the key on the next line
+        if urls.len() > found
+            && let Some(next) = tokens.peek()
+            && let Some(joined) = rejoin(&urls[urls.len() - 1], next)
+        {
+            let last = urls.len() - 1;
+            urls[last] = joined;
+            tokens.next();
+        }
+
+        if urls.len() == found && depth > 0 && token.contains('%') {
+            let decoded = percent_decode(token);
+            if decoded != token {
+                collect_urls(&decoded, depth - 1, urls);
+            }
+        }
+    }
+}
+
+/// Puts a link back together with the rest of it from the next token, but
+/// only when the link is broken on its own and the two make a valid one.
+fn rejoin(link: &str, next: &str) -> Option<String> {
+    if MegaUrl::parse(link).is_some() || find_host(next).is_some() {
+        return None;
+    }
+
+    let joined = format!("{link}{}", next.trim_end_matches(TRAILING));
+    MegaUrl::parse(&joined)?;
+    Some(normalize_mega_url(&joined))
+}
+
+/// Finds where a MEGA host starts in `text`, ignoring hosts like `omega.nz`.
+fn find_host(text: &str) -> Option<usize> {
+    HOSTS.iter().find_map(|host| {
+        text.match_indices(host)
+            .map(|(start, _)| start)
+            .find(|&start| {
+                !text[..start]
+                    .ends_with(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+    })
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Rewrites legacy `mega.nz/#!handle!key` file and `mega.nz/#F!handle!key`
+/// folder links, as well as links on the old `mega.co.nz` host, into the
+/// `https://mega.nz/file/...` and `https://mega.nz/folder/...` forms.
+///
+/// Anything that isn't a MEGA link is returned unchanged.
+pub fn normalize_mega_url(url: &str) -> String {
+    let Some(rest) = strip_host(url) else {
+        return url.to_string();
+    };
+
+    if let Some(legacy) = rest.strip_prefix("#F!")
+        && let Some((handle, key)) = legacy.split_once('!')
+    {
+        return match key.split_once('!') {
+            Some((key, child)) => format!("https://mega.nz/folder/{handle}#{key}/folder/{child}"),
+            None => format!("https://mega.nz/folder/{handle}#{key}"),
+        };
+    }
+
+    if let Some(legacy) = rest.strip_prefix("#!")
+        && let Some((handle, key)) = legacy.split_once('!')
+    {
+        return format!("https://mega.nz/file/{handle}#{key}");
+    }
+
+    format!("https://mega.nz/{rest}")
+}
+
+/// Returns what follows the host of a MEGA link, with or without scheme and `www.`.
+fn strip_host(url: &str) -> Option<&str> {
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    let url = url.strip_prefix("www.").unwrap_or(url);
+
+    HOSTS.iter().find_map(|host| url.strip_prefix(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_legacy_links() {
+        assert_eq!(
+            normalize_mega_url("https://mega.nz/#!AbCd!k3y"),
+            "https://mega.nz/file/AbCd#k3y"
+        );
+        assert_eq!(
+            normalize_mega_url("http://www.mega.co.nz/#F!AbCd!k3y"),
+            "https://mega.nz/folder/AbCd#k3y"
+        );
+        assert_eq!(
+            normalize_mega_url("mega.nz/#F!AbCd!k3y!EfGh"),
+            "https://mega.nz/folder/AbCd#k3y/folder/EfGh"
+        );
+        assert_eq!(
+            normalize_mega_url("mega.co.nz/file/AbCd#k3y"),
+            "https://mega.nz/file/AbCd#k3y"
+        );
+    }
+
+    #[test]
+    fn leaves_other_links_alone() {
+        assert_eq!(
+            normalize_mega_url("https://example.com/#!AbCd!k3y"),
+            "https://example.com/#!AbCd!k3y"
+        );
+        // not enough parts to rewrite
+        assert_eq!(
+            normalize_mega_url("mega.nz/#!AbCd"),
+            "https://mega.nz/#!AbCd"
+        );
+    }
+
+    #[test]
+    fn extracts_links_from_redirectors() {
+        assert_eq!(
+            extract_urls(
+                "see https://example.com/out.php?url=https%3A%2F%2Fmega.nz%2Ffile%2FAbCd%23k3y"
+            ),
+            ["https://mega.nz/file/AbCd#k3y"]
+        );
+        // encoded twice over
+        assert_eq!(
+            extract_urls(
+                "https://example.com/?to=https%253A%252F%252Fmega.nz%252Ffolder%252FAbCd%2523k3y"
+            ),
+            ["https://mega.nz/folder/AbCd#k3y"]
+        );
+    }
+
+    #[test]
+    fn extracts_links_from_query_strings() {
+        assert_eq!(
+            extract_urls("https://example.com/?a=mega.nz/file/AbCd#k1&b=mega.nz/file/EfGh#k2"),
+            [
+                "https://mega.nz/file/AbCd#k1",
+                "https://mega.nz/file/EfGh#k2"
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_lookalike_hosts() {
+        assert!(extract_urls("https://omega.nz/file/AbCd#k3y").is_empty());
+    }
+
+    #[test]
+    fn dedups_normalized_links_ignoring_case() {
+        assert_eq!(
+            dedup_urls([
+                "https://mega.nz/file/AbCd#k3y".to_string(),
+                "mega.co.nz/#!AbCd!k3y".to_string(),
+                "https://MEGA.nz/file/abcd#K3Y".to_string(),
+                "https://mega.nz/file/EfGh#k3y".to_string(),
+            ]),
+            [
+                "https://mega.nz/file/AbCd#k3y",
+                "https://mega.nz/file/EfGh#k3y"
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_file_and_folder_links() {
+        assert_eq!(
+            MegaUrl::parse("https://mega.nz/file/AbC-d_1#k3y"),
+            Some(MegaUrl::File {
+                handle: "AbC-d_1".to_string(),
+                key: "k3y".to_string(),
+            })
+        );
+        assert_eq!(
+            MegaUrl::parse("https://mega.nz/folder/AbCd#k3y"),
+            Some(MegaUrl::Folder {
+                handle: "AbCd".to_string(),
+                key: "k3y".to_string(),
+                subpath: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_legacy_links() {
+        assert_eq!(
+            MegaUrl::parse("https://mega.co.nz/#!AbCd!k3y"),
+            Some(MegaUrl::File {
+                handle: "AbCd".to_string(),
+                key: "k3y".to_string(),
+            })
+        );
+        assert_eq!(
+            MegaUrl::parse("mega.nz/#F!AbCd!k3y"),
+            Some(MegaUrl::Folder {
+                handle: "AbCd".to_string(),
+                key: "k3y".to_string(),
+                subpath: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_subpaths() {
+        let url = MegaUrl::parse("https://mega.nz/folder/AbCd#k3y/file/EfGh").unwrap();
+        assert_eq!(
+            url,
+            MegaUrl::Folder {
+                handle: "AbCd".to_string(),
+                key: "k3y".to_string(),
+                subpath: Some("file/EfGh".to_string()),
+            }
+        );
+        assert_eq!(url.to_string(), "https://mega.nz/folder/AbCd#k3y/file/EfGh");
+    }
+
+    #[test]
+    fn rejects_malformed_links() {
+        for url in [
+            "https://example.com/file/AbCd#k3y",
+            "https://mega.nz/file/AbCd",
+            "https://mega.nz/file/AbCd#",
+            "https://mega.nz/file/Ab.d#k3y",
+            "https://mega.nz/file/AbCd#k3y/file/EfGh",
+            "https://mega.nz/chat/AbCd#k3y",
+            "https://mega.nz/folder/AbCd#k3y/photo/EfGh",
+            "https://mega.nz/folder/AbCd#k3y/file/",
+        ] {
+            assert_eq!(MegaUrl::parse(url), None, "{url}");
+        }
+    }
+
+    #[test]
+    fn drops_trailing_punctuation() {
+        assert_eq!(
+            extract_urls("Grab it (https://mega.nz/file/AbCd#k3y), or https://mega.nz/#!EfGh!k3y."),
+            [
+                "https://mega.nz/file/AbCd#k3y",
+                "https://mega.nz/file/EfGh#k3y"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejoins_wrapped_links() {
+        assert_eq!(
+            rejoin("https://mega.nz/file/AbCd#", "k3y."),
+            Some("https://mega.nz/file/AbCd#k3y".to_string())
+        );
+        // already whole
+        assert_eq!(rejoin("https://mega.nz/file/AbCd#k3y", "more"), None);
+        // the next token is a link of its own
+        assert_eq!(
+            rejoin(
+                "https://mega.nz/file/AbCd#",
+                "https://mega.nz/file/EfGh#k3y"
+            ),
+            None
+        );
+        assert_eq!(rejoin("https://mega.nz/file/AbCd#", "not/a/key"), None);
+    }
+
+    #[test]
+    fn extracts_links_split_across_lines() {
+        assert_eq!(
+            extract_urls("https://mega.nz/folder/AbCd#\nk3y\nand https://mega.nz/file/EfGh#k3y"),
+            [
+                "https://mega.nz/folder/AbCd#k3y",
+                "https://mega.nz/file/EfGh#k3y"
+            ]
+        );
+    }
+}